@@ -0,0 +1,43 @@
+//! Command line interface of the application
+
+use clap::Parser;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Telegram bot of the QueensCorsar bridge
+#[derive(Parser, Debug, Clone)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the configuration file, created with the
+    /// default settings if missing
+    #[arg(long, value_name = "PATH", default_value = "bot_config.toml")]
+    pub config: PathBuf,
+
+    /// Minimal level of the logged records: trace, debug,
+    /// info, warning, error or critical
+    #[arg(long, value_name = "LEVEL", default_value = "debug", value_parser = parse_level)]
+    pub log_level: slog::Level,
+
+    /// Validate the configuration and exit, the file must exist
+    /// and is never upgraded
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Override a configuration value, taking precedence over
+    /// the environment and the file, e.g. `general.server_port=8443`
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+}
+
+fn parse_level(level: &str) -> Result<slog::Level, String> {
+    slog::Level::from_str(level).map_err(|_| format!("unknown log level '{}'", level))
+}
+
+fn parse_override(entry: &str) -> Result<(String, String), String> {
+    match entry.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got '{}'", entry)),
+    }
+}
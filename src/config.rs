@@ -3,6 +3,10 @@
 //!
 //! All available settings:
 //! ```toml
+//! \# Version of the settings layout, older files are migrated
+//! \# and written back on startup along with a backup
+//! config_version = VERSION
+//!
 //! [general] # Mandatory application settings
 //! \# Interface and port used to deploy a server listening
 //! \# for incoming telegram updates
@@ -18,19 +22,122 @@
 //! \# telegram api token
 //! token_var = 'VAR_NAME'
 //!
+//! \# File containing the telegram api token, like a docker
+//! \# secret or a systemd credential, preferred over the
+//! \# environment variable (optional)
+//! token_file = 'FILEPATH'
+//!
+//! \# Helper program and its arguments printing the telegram
+//! \# api token on its standard output, preferred over the
+//! \# environment variable (optional)
+//! token_command = ['PROGRAM', 'ARG']
+//!
 //! \# Path to the socket used to receive data from other bots
 //! sock_addr = 'FILEPATH'
 //!
+//! \# How telegram updates are received, either through the
+//! \# webhook server or by long polling (optional, webhook
+//! \# by default)
+//! mode = 'webhook' | 'polling'
+//!
+//! \# Path on which telegram posts the updates, it must match
+//! \# the path of the registered webhook url (optional, '/'
+//! \# by default)
+//! webhook_path = '/PATH'
+//!
+//! \# Time allowed to finish the ongoing work once a stop is
+//! \# requested, in seconds (optional, 10 by default)
+//! shutdown_timeout = SECONDS
+//!
+//! \# Secret token telegram sends along with every webhook
+//! \# request, made of 1 to 256 characters among A-Z, a-z,
+//! \# 0-9, _ and - (optional, requires the [webhook] section)
+//! webhook_secret = 'SECRET'
+//!
+//! [server] # Optional webhook server settings
+//! \# Maximal number of connections served at once, others
+//! \# are answered with 503 until a slot frees up
+//! max_connections = NUMBER
+//!
+//! \# Time allowed to receive a whole request and to write
+//! \# a response, in seconds
+//! read_timeout = SECONDS
+//! write_timeout = SECONDS
+//!
+//! \# Time a kept alive connection may stay idle, in seconds
+//! idle_timeout = SECONDS
+//!
+//! \# Delay telegram is asked to wait before retrying a refused
+//! \# request, in seconds
+//! retry_after = SECONDS
+//!
+//! [supervisor] # Optional settings of the servers restarts
+//! \# Number of consecutive restarts of a failed server before
+//! \# the application gives up and exits
+//! max_restarts = NUMBER
+//!
+//! \# Delay before the first restart, doubled on every consecutive
+//! \# failure up to the maximal delay, in seconds
+//! initial_backoff = SECONDS
+//! max_backoff = SECONDS
+//!
+//! [webhook] # Optional webhook registration settings
+//! \# Public url of the webhook server registered on startup
+//! url = 'https://HOST:PORT/PATH'
+//!
+//! \# Maximal number of simultaneous connections telegram
+//! \# opens to deliver updates (optional)
+//! max_connections = NUMBER
+//!
+//! \# Kinds of updates telegram should deliver (optional)
+//! allowed_updates = ['message', ...]
+//!
+//! [polling] # Optional long polling settings
+//! \# File keeping the offset of the last received update
+//! offset_path = 'FILEPATH'
+//!
+//! \# Duration of a single long polling request in seconds
+//! timeout = SECONDS
+//!
 //! [integrations] # Optional integration settings
 //! \# Filepath of the listener socket of the discord bot
 //! discord = 'FILEPATH'
+//!
+//! [storage] # Optional message store settings
+//! \# File keeping track of the relayed messages
+//! path = 'FILEPATH'
+//!
+//! \# Number of days after which relayed messages are forgotten
+//! retention_days = DAYS
+//!
+//! [media] # Optional media forwarding settings
+//! \# Directory where files attached to telegram messages are
+//! \# downloaded for the other bots to upload them
+//! download_dir = 'DIRPATH'
+//!
+//! [[bridge]] # Routing table, one entry per bridged telegram chat
+//! \# Identifier of the telegram group chat
+//! telegram_chat = CHAT_ID
+//!
+//! \# Discord server and its text channels bridged with the chat
+//! discord_server = 'SERVER_ID'
+//! discord_channels = ['CHANNEL_ID', ...]
 //! ```
+//!
+//! Every setting can be overridden by an environment variable
+//! named after its path, e.g. `QCTG_GENERAL__SERVER_PORT`, or by
+//! the `--set general.server_port=PORT` command line flag. The
+//! command line takes precedence over the environment, which
+//! takes precedence over the file and the defaults. Overriding
+//! values are read as TOML values, a value like `8443` or `true`
+//! given to a text setting is taken as the text itself.
 
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
 
 use crate::prelude::*;
 
@@ -49,8 +156,19 @@ pub struct ServersSection {
 /// - `certificate_path`: Server's certificate used to authenticate our server
 /// - `token_var`: Name of the environment variable used to retrieve telegram
 ///   api token
+/// - `token_file`: File containing the api token, takes precedence over
+///   `token_command` and `token_var`
+/// - `token_command`: Program and arguments printing the api token on their
+///   standard output, takes precedence over `token_var`
 /// - `sock_addr`: Path to the socket used to receive data from other bots
 ///   via qcproto protocol
+/// - `mode`: Source of telegram updates, the webhook server or long polling
+/// - `webhook_path`: Path of the webhook server on which telegram posts
+///   the updates, requests on other paths are refused
+/// - `shutdown_timeout`: Time in seconds allowed to drain the ongoing
+///   connections and dispatches on SIGTERM or SIGINT
+/// - `webhook_secret`: Secret token registered with the webhook and sent
+///   back by telegram with every update, masked in the logs
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct GeneralSection {
     pub server_ip: String,
@@ -58,19 +176,259 @@ pub struct GeneralSection {
     pub private_key_path: String,
     pub certificate_path: String,
     pub token_var: String,
+    pub token_file: Option<PathBuf>,
+    pub token_command: Option<Vec<String>>,
     pub sock_addr: PathBuf,
+    #[serde(default)]
+    pub mode: UpdateMode,
+    #[serde(default = "default_webhook_path")]
+    pub webhook_path: String,
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    pub webhook_secret: Option<Secret<String>>,
+}
+
+/// Where the telegram api token is read from
+#[derive(Clone, Copy, Debug)]
+pub enum TokenSource<'a> {
+    File(&'a Path),
+    Command(&'a [String]),
+    Environment(&'a str),
+}
+
+impl std::fmt::Display for TokenSource<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenSource::File(path) => write!(f, "file '{}'", path.display()),
+            TokenSource::Command(command) => write!(f, "command '{}'", command.join(" ")),
+            TokenSource::Environment(var) => write!(f, "environment variable '{}'", var),
+        }
+    }
+}
+
+impl GeneralSection {
+    /// Source of the api token, the file is preferred over the
+    /// command which is preferred over the environment
+    pub fn token_source(&self) -> TokenSource<'_> {
+        if let Some(ref path) = self.token_file {
+            TokenSource::File(path)
+        } else if let Some(ref command) = self.token_command {
+            TokenSource::Command(command)
+        } else {
+            TokenSource::Environment(&self.token_var)
+        }
+    }
+}
+
+fn default_webhook_path() -> String {
+    "/".to_owned()
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+/// Source of the telegram updates
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateMode {
+    /// Telegram pushes updates to our webhook server
+    #[default]
+    Webhook,
+    /// We pull updates with `getUpdates` requests
+    Polling,
+}
+
+/// Settings of the webhook server
+///
+/// Available settings:
+/// - `max_connections`: Maximal number of connections served at once,
+///   connections above the limit are answered with 503
+/// - `read_timeout`: Time allowed to receive a whole request in seconds
+/// - `write_timeout`: Time allowed to write a response in seconds
+/// - `idle_timeout`: Time a kept alive connection may stay idle between
+///   two requests in seconds
+/// - `retry_after`: Delay sent in the `Retry-After` header of refused
+///   requests in seconds
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HttpServerSection {
+    pub max_connections: usize,
+    pub read_timeout: u64,
+    pub write_timeout: u64,
+    pub idle_timeout: u64,
+    pub retry_after: u64,
+}
+
+impl Default for HttpServerSection {
+    fn default() -> Self {
+        Self {
+            max_connections: 64,
+            read_timeout: 10,
+            write_timeout: 10,
+            idle_timeout: 30,
+            retry_after: 5,
+        }
+    }
+}
+
+/// Settings of the supervision of the update and command servers
+///
+/// Available settings:
+/// - `max_restarts`: Number of consecutive restarts of a failed server
+///   before the whole application shuts down
+/// - `initial_backoff`: Delay in seconds before the first restart, doubled
+///   on every consecutive failure
+/// - `max_backoff`: Upper bound of the delay between two restarts in seconds
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SupervisorSection {
+    pub max_restarts: u32,
+    pub initial_backoff: u64,
+    pub max_backoff: u64,
+}
+
+impl Default for SupervisorSection {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            initial_backoff: 1,
+            max_backoff: 60,
+        }
+    }
+}
+
+/// Webhook registration settings
+///
+/// Available settings:
+/// - `url`: Public url of the webhook server, registered through `setWebhook`
+///   along with the server's certificate whenever telegram has a different one
+/// - `max_connections`: Maximal number of simultaneous connections telegram
+///   opens to deliver updates
+/// - `allowed_updates`: Kinds of updates telegram should deliver
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WebhookSection {
+    pub url: String,
+    pub max_connections: Option<u32>,
+    pub allowed_updates: Option<Vec<String>>,
+}
+
+/// Long polling settings
+///
+/// Available settings:
+/// - `offset_path`: File keeping the offset of the last received update
+///   to resume from it after a restart
+/// - `timeout`: Duration of a single long polling request in seconds
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PollingSection {
+    pub offset_path: PathBuf,
+    pub timeout: u32,
+}
+
+impl Default for PollingSection {
+    fn default() -> Self {
+        Self {
+            offset_path: PathBuf::from("update_offset"),
+            timeout: 30,
+        }
+    }
+}
+
+/// Settings of the store keeping track of relayed messages
+///
+/// Available settings:
+/// - `path`: Location of the append-only log of relayed messages
+/// - `retention_days`: Number of days after which a relayed message is
+///   forgotten and removed from the log during compaction
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StorageSection {
+    pub path: PathBuf,
+    pub retention_days: u32,
+}
+
+impl Default for StorageSection {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("relayed_messages.log"),
+            retention_days: 30,
+        }
+    }
+}
+
+/// Settings of the media forwarding
+///
+/// Available settings:
+/// - `download_dir`: Directory shared with the other bots where files
+///   attached to telegram messages are downloaded
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MediaSection {
+    pub download_dir: PathBuf,
+}
+
+impl Default for MediaSection {
+    fn default() -> Self {
+        Self {
+            download_dir: PathBuf::from("/tmp/qcorsar.tg.media"),
+        }
+    }
+}
+
+/// A single route of the bridge between a telegram chat
+/// and discord text channels
+///
+/// Available settings:
+/// - `telegram_chat`: Identifier of the bridged telegram chat
+/// - `discord_server`: Identifier of the discord server owning the channels
+/// - `discord_channels`: Identifiers of the discord text channels receiving
+///   messages from the telegram chat and forwarding their own messages back
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BridgeSection {
+    pub telegram_chat: i64,
+    pub discord_server: String,
+    pub discord_channels: Vec<String>,
+}
+
+fn current_config_version() -> u32 {
+    CONFIG_VERSION
 }
 
 /// Main application config structure
 ///
+/// `config_version` holds the version of the settings layout,
+/// files written by older releases are migrated when loaded
+///
 /// Available sections:
 /// - *general*: All the mandatory application settings
+/// - *server*: Limits and timeouts of the webhook server
+/// - *supervisor*: Restart policy of the failed servers
+/// - *webhook*: Settings of the webhook registration, the webhook is left
+///   untouched if omitted
+/// - *polling*: Settings of the long polling update source
 /// - *integrations*: Known sockets of other bots able to communicate via
 ///   qcproto protocol
+/// - *storage*: Settings of the relayed messages store
+/// - *media*: Settings of the media forwarding
+/// - *bridge*: Routing table between telegram chats and discord channels,
+///   messages from chats without a route are dropped
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Config {
+    #[serde(default = "current_config_version")]
+    pub config_version: u32,
     pub general: GeneralSection,
+    #[serde(default)]
+    pub server: HttpServerSection,
+    #[serde(default)]
+    pub supervisor: SupervisorSection,
+    pub webhook: Option<WebhookSection>,
+    #[serde(default)]
+    pub polling: PollingSection,
     pub integrations: Option<ServersSection>,
+    #[serde(default)]
+    pub storage: StorageSection,
+    #[serde(default)]
+    pub media: MediaSection,
+    // An empty array would be written after the tables, which
+    // TOML does not allow
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bridge: Vec<BridgeSection>,
 }
 
 impl Default for Config {
@@ -103,21 +461,902 @@ where
     Ok(default_config)
 }
 
-pub fn read<T>(cfg_path: &str) -> UResult<T>
+/// Describe a parse error with the position of the faulty
+/// setting and the line it sits on
+fn parse_error(cfg_path: &str, contents: &str, why: toml::de::Error) -> String {
+    match why.line_col() {
+        Some((line, col)) => format!(
+            "{}:{}:{}: {}\n    {} | {}",
+            cfg_path,
+            line + 1,
+            col + 1,
+            why,
+            line + 1,
+            contents.lines().nth(line).unwrap_or_default()
+        ),
+        None => format!("{}: {}", cfg_path, why),
+    }
+}
+
+/// Parse the file contents, errors point to the line and
+/// column of the faulty setting
+fn parse<T>(cfg_path: &str, contents: &str) -> UResult<T>
 where
     T: DeserializeOwned,
 {
-    let contents = std::fs::read_to_string(&cfg_path)?;
-    let config = toml::from_str::<T>(&contents)?;
-    Ok(config)
+    toml::from_str::<T>(contents).map_err(|why| parse_error(cfg_path, contents, why).into())
 }
 
-pub fn read_or_create<T>(cfg_path: &str) -> UResult<T>
-where
-    T: Default + DeserializeOwned + Serialize,
-{
-    match read::<T>(cfg_path) {
-        Ok(v) => Ok(v),
-        Err(_) => create(cfg_path),
+/// Every problem found in a configuration
+#[derive(Debug)]
+pub struct ValidationErrors(pub Vec<String>);
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} problem(s) found in the configuration", self.0.len())?;
+        for problem in self.0.iter() {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Check whether the directory which would hold the path
+/// exists, relative paths without a directory always do
+fn parent_exists(path: &Path) -> bool {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.is_dir(),
+        _ => true,
+    }
+}
+
+/// Check whether telegram accepts the webhook secret token
+fn is_valid_secret(secret: &str) -> bool {
+    (1..=256).contains(&secret.len())
+        && secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Check the settings which can be wrong even though the
+/// file parses, every problem is reported at once
+pub fn validate(config: &Config) -> Result<(), ValidationErrors> {
+    let mut problems = Vec::new();
+    let general = &config.general;
+
+    if general.mode == UpdateMode::Webhook {
+        for (key, path) in [
+            ("general.private_key_path", &general.private_key_path),
+            ("general.certificate_path", &general.certificate_path),
+        ] {
+            if let Err(why) = std::fs::File::open(path) {
+                problems.push(format!("{}: cannot read '{}': {}", key, path, why));
+            }
+        }
+        if !general.webhook_path.starts_with('/') {
+            problems.push(format!(
+                "general.webhook_path: '{}' must start with '/'",
+                general.webhook_path
+            ));
+        }
+    }
+    match general.token_source() {
+        TokenSource::File(path) => {
+            if let Err(why) = std::fs::File::open(path) {
+                problems.push(format!(
+                    "general.token_file: cannot read '{}': {}",
+                    path.display(),
+                    why
+                ));
+            }
+        }
+        TokenSource::Command(command) => {
+            if !matches!(command.first(), Some(program) if !program.is_empty()) {
+                problems.push("general.token_command: the program is missing".to_owned());
+            }
+        }
+        TokenSource::Environment(var) => {
+            if std::env::var(var).is_err() {
+                problems.push(format!(
+                    "general.token_var: the environment variable '{}' is not set",
+                    var
+                ));
+            }
+        }
+    }
+    if !parent_exists(&general.sock_addr) {
+        problems.push(format!(
+            "general.sock_addr: the directory of '{}' does not exist",
+            general.sock_addr.display()
+        ));
+    }
+    if let Some(ref webhook) = config.webhook {
+        match webhook.url.parse::<http::Uri>() {
+            Ok(url) if url.scheme_str() == Some("https") => {
+                // Telegram posts the updates on the path of the url
+                if general.mode == UpdateMode::Webhook && url.path() != general.webhook_path {
+                    problems.push(format!(
+                        "webhook.url: the path '{}' differs from general.webhook_path '{}'",
+                        url.path(),
+                        general.webhook_path
+                    ));
+                }
+            }
+            _ => problems.push(format!("webhook.url: '{}' must be an https url", webhook.url)),
+        }
+    }
+    if let Some(ref secret) = general.webhook_secret {
+        // The secret is only given to telegram along with the
+        // webhook, every update would be refused otherwise
+        if config.webhook.is_none() {
+            problems.push(
+                "general.webhook_secret: requires the [webhook] section to be registered".to_owned(),
+            );
+        }
+        if !is_valid_secret(secret.expose()) {
+            problems.push(
+                "general.webhook_secret: must be 1 to 256 characters among A-Z, a-z, 0-9, _ and -"
+                    .to_owned(),
+            );
+        }
+    }
+    if config.server.max_connections == 0 {
+        problems.push("server.max_connections: must be at least 1".to_owned());
+    }
+    if config.supervisor.initial_backoff > config.supervisor.max_backoff {
+        problems.push("supervisor.initial_backoff: must not exceed max_backoff".to_owned());
+    }
+    if !parent_exists(&config.storage.path) {
+        problems.push(format!(
+            "storage.path: the directory of '{}' does not exist",
+            config.storage.path.display()
+        ));
+    }
+    for (index, route) in config.bridge.iter().enumerate() {
+        if route.discord_server.is_empty() {
+            problems.push(format!(
+                "bridge[{}]: the telegram chat {} has no discord server",
+                index, route.telegram_chat
+            ));
+        }
+        if route.discord_channels.is_empty() {
+            problems.push(format!(
+                "bridge[{}]: the telegram chat {} has no discord channel",
+                index, route.telegram_chat
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationErrors(problems))
+    }
+}
+
+/// Version of the settings layout written by this release
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Upgrade of a document from the version matching its index in
+/// `MIGRATIONS` to the next one, returns the description of the
+/// changes made
+type Migration = fn(&mut Table) -> Vec<String>;
+
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [migrate_v0_to_v1];
+
+/// Documents written before `config_version` existed share the
+/// current layout, only the version has to be stamped
+fn migrate_v0_to_v1(_document: &mut Table) -> Vec<String> {
+    Vec::new()
+}
+
+/// Version of the document, unversioned documents predate
+/// the `config_version` key
+fn document_version(document: &Table) -> UResult<u32> {
+    match document.get("config_version") {
+        None => Ok(0),
+        Some(Value::Integer(version)) if *version >= 0 => Ok(*version as u32),
+        Some(value) => {
+            Err(format!("config_version: expected a version number, got {}", value).into())
+        }
+    }
+}
+
+/// Upgrade the document step by step up to the current version,
+/// returns the description of every change made
+fn migrate(document: &mut Table) -> UResult<Vec<String>> {
+    let version = document_version(document)?;
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "config_version: {} is newer than the supported version {}",
+            version, CONFIG_VERSION
+        )
+        .into());
+    }
+
+    let mut changes = Vec::new();
+    for from in version..CONFIG_VERSION {
+        changes.extend(MIGRATIONS[from as usize](document));
+        document.insert("config_version".to_owned(), Value::Integer(from as i64 + 1));
+        changes.push(format!("config_version: {} -> {}", from, from + 1));
+    }
+    Ok(changes)
+}
+
+/// Set the version in the text of the file, keeping its comments
+/// and layout. The top-level `config_version` line is replaced, or
+/// one is added at the beginning of the file
+fn stamp_version(contents: &str, version: u32) -> String {
+    let stamp = format!("config_version = {}", version);
+    let mut stamped = String::with_capacity(contents.len() + stamp.len() + 1);
+    let mut top_level = true;
+    let mut found = false;
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        top_level &= !trimmed.starts_with('[');
+        let is_version = trimmed
+            .strip_prefix("config_version")
+            .is_some_and(|rest| rest.trim_start().starts_with('='));
+        if top_level && !found && is_version {
+            stamped.push_str(&stamp);
+            found = true;
+        } else {
+            stamped.push_str(line);
+        }
+        stamped.push('\n');
+    }
+    if found {
+        stamped
+    } else {
+        format!("{}\n{}", stamp, stamped)
+    }
+}
+
+/// Replace the file with the migrated document, the original is
+/// kept next to it with the version it had. Returns the path of
+/// the backup
+///
+/// When the migration only changed the version, the original text
+/// is kept with its new version stamp, otherwise the document is
+/// written anew without the comments of the file
+fn write_migrated(path: &Path, version: u32, contents: &str, document: &Table) -> UResult<PathBuf> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)?;
+
+    let stamped = stamp_version(contents, document_version(document)?);
+    let migrated = match toml::from_str::<Table>(&stamped) {
+        Ok(ref stamped_document) if stamped_document == document => stamped,
+        _ => toml::to_string(&Value::Table(document.clone()))?,
+    };
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, migrated)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(backup)
+}
+
+/// Prefix of the environment variables overriding settings
+pub const ENV_PREFIX: &str = "QCTG_";
+
+/// Origin of a configuration value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueSource {
+    Default,
+    File,
+    Environment,
+    CommandLine,
+}
+
+impl std::fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ValueSource::Default => "default",
+            ValueSource::File => "file",
+            ValueSource::Environment => "environment",
+            ValueSource::CommandLine => "command line",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Origin of every setting, indexed by its dotted path
+pub type ValueSources = BTreeMap<String, ValueSource>;
+
+/// Sources the configuration is assembled from
+#[derive(Clone, Default)]
+pub struct ConfigSources {
+    /// Path to the configuration file
+    pub path: PathBuf,
+    /// Values given on the command line as dotted paths
+    /// along with their raw values
+    pub overrides: Vec<(String, String)>,
+}
+
+/// Overrides may carry secrets, only their keys are shown
+impl std::fmt::Debug for ConfigSources {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let overrides: Vec<(&str, &str)> = self
+            .overrides
+            .iter()
+            .map(|(key, _)| (key.as_str(), REDACTED))
+            .collect();
+        f.debug_struct("ConfigSources")
+            .field("path", &self.path)
+            .field("overrides", &overrides)
+            .finish()
+    }
+}
+
+/// Setting overridden by the environment or the command line
+struct Override {
+    /// Dotted path of the setting
+    key: String,
+    raw: String,
+    source: ValueSource,
+}
+
+/// Parse a raw override as a TOML value, anything which is
+/// not valid TOML is taken as a plain string
+fn parse_raw_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
+
+/// Record the source of every leaf of the value
+fn record_sources(value: &Value, path: &str, source: ValueSource, sources: &mut ValueSources) {
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                record_sources(value, &format!("{}.{}", path, key), source, sources);
+            }
+        }
+        _ => {
+            // Overriding a whole table drops the sources of its
+            // former leaves
+            let nested = format!("{}.", path);
+            sources.retain(|k, _| !k.starts_with(&nested));
+            sources.insert(path.to_owned(), source);
+        }
+    }
+}
+
+/// Merge the overlay into the base table, tables are merged
+/// recursively and any other value is replaced
+fn merge(base: &mut Table, overlay: Table, prefix: &str, source: ValueSource, sources: &mut ValueSources) {
+    for (key, value) in overlay {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(nested)) => {
+                merge(existing, nested, &path, source, sources)
+            }
+            (_, value) => {
+                record_sources(&value, &path, source, sources);
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Build a table holding the value at the dotted path
+fn nested_table(path: &[String], value: Value) -> Table {
+    let mut table = Table::new();
+    match path.split_first() {
+        Some((key, [])) => {
+            table.insert(key.clone(), value);
+        }
+        Some((key, rest)) => {
+            table.insert(key.clone(), Value::Table(nested_table(rest, value)));
+        }
+        None => (),
+    }
+    table
+}
+
+/// Overrides found in the environment, `QCTG_GENERAL__SERVER_PORT`
+/// overrides `general.server_port`
+fn environment_overrides() -> Vec<Override> {
+    std::env::vars()
+        .filter_map(|(name, raw)| {
+            let path = name.strip_prefix(ENV_PREFIX)?;
+            let path: Vec<String> = path.split("__").map(|s| s.to_lowercase()).collect();
+            Some(Override {
+                key: path.join("."),
+                raw,
+                source: ValueSource::Environment,
+            })
+        })
+        .collect()
+}
+
+/// Setting refused while building the config
+struct SettingError {
+    /// Dotted path of the setting
+    key: String,
+    /// Origin of the setting, if known
+    source: Option<ValueSource>,
+    why: toml::de::Error,
+}
+
+/// Origin of the setting or of the closest table or array
+/// holding it
+fn source_of(sources: &ValueSources, key: &str) -> Option<ValueSource> {
+    let mut key = key;
+    loop {
+        if let Some(source) = sources.get(key) {
+            return Some(*source);
+        }
+        key = &key[..key.rfind(['.', '['])?];
+    }
+}
+
+/// Describe the refused setting, errors in the file point to the
+/// line and column of the setting
+fn setting_error(cfg_path: &str, contents: Option<&str>, error: SettingError) -> String {
+    match (error.source, contents) {
+        (Some(ValueSource::File), Some(contents)) => {
+            // The file alone is parsed again to locate the setting,
+            // it may lack settings provided by the other sources
+            let mut deserializer = toml::Deserializer::new(contents);
+            match serde_path_to_error::deserialize::<_, Config>(&mut deserializer) {
+                Err(why) if why.path().to_string() == error.key => {
+                    parse_error(cfg_path, contents, why.into_inner())
+                }
+                _ => format!("{}: {}", cfg_path, error.why),
+            }
+        }
+        (Some(source), _) if source != ValueSource::Default => {
+            format!("{} (from the {}): {}", error.key, source, error.why)
+        }
+        _ => format!("{}: {}", error.key, error.why),
+    }
+}
+
+/// Apply the overrides to the settings and build the config.
+/// Values typed as numbers or booleans which a text setting
+/// refuses are applied again as strings
+fn apply_overrides(
+    base: Table,
+    base_sources: ValueSources,
+    overrides: &[Override],
+) -> Result<(Config, ValueSources), SettingError> {
+    let mut textual = HashSet::new();
+    loop {
+        let mut merged = base.clone();
+        let mut sources = base_sources.clone();
+        for entry in overrides {
+            let value = if textual.contains(&entry.key) {
+                Value::String(entry.raw.clone())
+            } else {
+                parse_raw_value(&entry.raw)
+            };
+            let path: Vec<String> = entry.key.split('.').map(|s| s.to_owned()).collect();
+            let overlay = nested_table(&path, value);
+            merge(&mut merged, overlay, "", entry.source, &mut sources);
+        }
+
+        match serde_path_to_error::deserialize::<_, Config>(Value::Table(merged)) {
+            Ok(config) => return Ok((config, sources)),
+            Err(why) => {
+                let key = why.path().to_string();
+                let typed = overrides
+                    .iter()
+                    .any(|entry| entry.key == key && !parse_raw_value(&entry.raw).is_str());
+                if !typed || textual.contains(&key) {
+                    return Err(SettingError {
+                        source: source_of(&sources, &key),
+                        key,
+                        why: why.into_inner(),
+                    });
+                }
+                textual.insert(key);
+            }
+        }
+    }
+}
+
+/// Whether loading the configuration may write its file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadMode {
+    /// A missing file is created with the default settings and
+    /// an outdated one is upgraded in place
+    Startup,
+    /// The file is never written, outdated files are only
+    /// upgraded in memory. A missing file is an error, so that
+    /// a reload never falls back to the defaults
+    ReadOnly,
+}
+
+/// Assemble the configuration from the defaults, the file, the
+/// environment and the command line, in increasing precedence
+pub fn load(
+    config_sources: &ConfigSources,
+    mode: LoadMode,
+    logger: &slog::Logger,
+) -> UResult<(Config, ValueSources)> {
+    let path = config_sources.path.to_string_lossy();
+    let mut sources = ValueSources::new();
+    let mut merged = Table::new();
+
+    let defaults = Value::try_from(Config::default())?;
+    if let Value::Table(defaults) = defaults {
+        merge(&mut merged, defaults, "", ValueSource::Default, &mut sources);
     }
+
+    let contents = if config_sources.path.exists() {
+        Some(std::fs::read_to_string(&config_sources.path)?)
+    } else {
+        None
+    };
+    let file = if let Some(ref contents) = contents {
+        let mut document = parse::<Table>(&path, contents)?;
+        let original_version = document_version(&document)?;
+        let changes = migrate(&mut document)?;
+        for change in changes.iter() {
+            info!(logger, "Configuration migrated"; "change" => change);
+        }
+        match mode {
+            LoadMode::Startup if !changes.is_empty() => {
+                let backup = write_migrated(&config_sources.path, original_version, contents, &document)?;
+                warn!(logger, "Configuration file upgraded to the current version";
+                    "from" => original_version,
+                    "to" => CONFIG_VERSION,
+                    "backup" => backup.to_string_lossy().into_owned()
+                );
+            }
+            LoadMode::ReadOnly if !changes.is_empty() => {
+                warn!(logger, "Configuration file is outdated, it will be upgraded on the next start";
+                    "from" => original_version,
+                    "to" => CONFIG_VERSION
+                );
+            }
+            _ => (),
+        }
+        document
+    } else {
+        match mode {
+            LoadMode::Startup => {
+                create::<Config>(&path)?;
+                info!(logger, "Created the configuration file with the default settings"; "path" => %path);
+            }
+            LoadMode::ReadOnly => {
+                return Err(format!("{}: the configuration file does not exist", path).into());
+            }
+        }
+        Table::new()
+    };
+    merge(&mut merged, file, "", ValueSource::File, &mut sources);
+
+    let mut overrides = environment_overrides();
+    overrides.extend(config_sources.overrides.iter().map(|(key, raw)| Override {
+        key: key.clone(),
+        raw: raw.clone(),
+        source: ValueSource::CommandLine,
+    }));
+    apply_overrides(merged, sources, &overrides)
+        .map_err(|error| setting_error(&path, contents.as_deref(), error).into())
+}
+
+/// Log the origin of every setting, the values themselves are
+/// left out as some of them are secrets
+pub fn log_sources(logger: &slog::Logger, sources: &ValueSources) {
+    for (key, source) in sources {
+        match source {
+            ValueSource::Default => debug!(logger, "Configuration value"; "key" => key, "source" => %source),
+            _ => info!(logger, "Configuration value"; "key" => key, "source" => %source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a scratch file unique to the test
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qctg-{}-{}.toml", name, std::process::id()))
+    }
+
+    #[test]
+    fn create_writes_a_readable_default_config() {
+        let path = scratch_path("create");
+        let created = create::<Config>(&path.to_string_lossy()).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let read_back = parse::<Config>(&path.to_string_lossy(), &contents).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(created.config_version, CONFIG_VERSION);
+        assert_eq!(read_back.general.server_port, created.general.server_port);
+        assert!(read_back.bridge.is_empty());
+    }
+
+    #[test]
+    fn create_keeps_the_bridge_routes() {
+        let mut config = Config::default();
+        config.bridge.push(BridgeSection {
+            telegram_chat: -100,
+            discord_server: "server".to_owned(),
+            discord_channels: vec!["general".to_owned()],
+        });
+        let serialized = toml::to_string(&config).unwrap();
+        let parsed: Config = toml::from_str(&serialized).unwrap();
+        assert_eq!(parsed.bridge.len(), 1);
+        assert_eq!(parsed.bridge[0].discord_channels, ["general"]);
+    }
+
+    fn overridden(overrides: &[(&str, &str)]) -> UResult<Config> {
+        let base = match Value::try_from(Config::default()).unwrap() {
+            Value::Table(base) => base,
+            _ => unreachable!(),
+        };
+        let overrides: Vec<Override> = overrides
+            .iter()
+            .map(|(key, raw)| Override {
+                key: key.to_string(),
+                raw: raw.to_string(),
+                source: ValueSource::CommandLine,
+            })
+            .collect();
+        apply_overrides(base, ValueSources::new(), &overrides)
+            .map(|(config, _)| config)
+            .map_err(|error| setting_error("bot_config.toml", None, error).into())
+    }
+
+    #[test]
+    fn overrides_are_typed() {
+        let config = overridden(&[
+            ("general.server_port", "9000"),
+            ("general.mode", "polling"),
+            ("general.token_command", "['pass', 'show']"),
+        ])
+        .unwrap();
+        assert_eq!(config.general.server_port, 9000);
+        assert_eq!(config.general.mode, UpdateMode::Polling);
+        assert_eq!(config.general.token_command.unwrap(), ["pass", "show"]);
+    }
+
+    #[test]
+    fn text_settings_accept_numbers_and_booleans() {
+        let config = overridden(&[
+            ("general.webhook_secret", "20240101"),
+            ("general.token_var", "1"),
+            ("general.server_ip", "true"),
+        ])
+        .unwrap();
+        assert_eq!(config.general.webhook_secret.unwrap().expose(), "20240101");
+        assert_eq!(config.general.token_var, "1");
+        assert_eq!(config.general.server_ip, "true");
+    }
+
+    #[test]
+    fn mistyped_overrides_are_reported() {
+        let why = overridden(&[("general.server_port", "abc")]).unwrap_err();
+        assert!(
+            why.to_string().starts_with("general.server_port (from the command line)"),
+            "{}",
+            why
+        );
+    }
+
+    fn load_file(name: &str, contents: &str) -> UResult<Config> {
+        let path = scratch_path(name);
+        std::fs::write(&path, contents).unwrap();
+        let sources = ConfigSources {
+            path: path.clone(),
+            overrides: Vec::new(),
+        };
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let result = load(&sources, LoadMode::ReadOnly, &logger).map(|(config, _)| config);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn file_errors_point_to_the_setting() {
+        let why = load_file("mistyped", "config_version = 1\n[general]\nserver_port = 'abc'\n")
+            .unwrap_err()
+            .to_string();
+        assert!(why.contains("mistyped") && why.contains(":3:15: "), "{}", why);
+
+        let why = load_file(
+            "incomplete",
+            "config_version = 1\n[[bridge]]\ntelegram_chat = 1\ndiscord_server = 's'\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(why.contains(":2:1: ") && why.contains("discord_channels"), "{}", why);
+    }
+
+    #[test]
+    fn partial_files_are_completed_by_the_defaults() {
+        let config = load_file("partial", "config_version = 1\n[general]\nserver_port = 9000\n").unwrap();
+        assert_eq!(config.general.server_port, 9000);
+        assert_eq!(config.general.server_ip, Config::default().general.server_ip);
+    }
+
+    /// Problems found in the default config with the given changes
+    fn problems(change: impl FnOnce(&mut Config)) -> Vec<String> {
+        let mut config = Config::default();
+        change(&mut config);
+        match validate(&config) {
+            Ok(()) => Vec::new(),
+            Err(ValidationErrors(problems)) => problems,
+        }
+    }
+
+    fn webhook(url: &str) -> WebhookSection {
+        WebhookSection {
+            url: url.to_owned(),
+            max_connections: None,
+            allowed_updates: None,
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let found = problems(|config| {
+            config.general.private_key_path = "/nonexistent/private.key".to_owned();
+            config.general.webhook_path = "updates".to_owned();
+            config.general.token_command = Some(Vec::new());
+            config.webhook = Some(webhook("http://example.org/updates"));
+            config.server.max_connections = 0;
+            config.supervisor.initial_backoff = 120;
+            config.storage.path = PathBuf::from("/nonexistent/relayed_messages.log");
+            config.bridge.push(BridgeSection {
+                telegram_chat: -100,
+                discord_server: String::new(),
+                discord_channels: Vec::new(),
+            });
+        });
+        for key in [
+            "general.private_key_path",
+            "general.webhook_path",
+            "general.token_command",
+            "webhook.url",
+            "server.max_connections",
+            "supervisor.initial_backoff",
+            "storage.path",
+            "bridge[0]: the telegram chat -100 has no discord server",
+            "bridge[0]: the telegram chat -100 has no discord channel",
+        ] {
+            assert!(found.iter().any(|p| p.starts_with(key)), "{} missing from {:?}", key, found);
+        }
+    }
+
+    #[test]
+    fn the_webhook_url_matches_the_served_path() {
+        let url_problems = |url: &str, path: &str| -> Vec<String> {
+            let (url, path) = (url.to_owned(), path.to_owned());
+            problems(move |config| {
+                config.webhook = Some(webhook(&url));
+                config.general.webhook_path = path;
+            })
+            .into_iter()
+            .filter(|p| p.starts_with("webhook.url"))
+            .collect()
+        };
+
+        assert!(url_problems("https://example.org:8443/updates", "/updates").is_empty());
+        assert!(url_problems("https://example.org", "/").is_empty());
+        assert_eq!(url_problems("https://example.org/updates", "/").len(), 1);
+        assert_eq!(url_problems("https://example.org/", "/updates").len(), 1);
+        assert_eq!(url_problems("not a url", "/").len(), 1);
+    }
+
+    #[test]
+    fn webhook_secrets_are_checked() {
+        let secret_problems = |problems: Vec<String>| -> Vec<String> {
+            problems
+                .into_iter()
+                .filter(|p| p.starts_with("general.webhook_secret"))
+                .collect()
+        };
+
+        let found = secret_problems(problems(|config| {
+            config.general.webhook_secret = Some(Secret::new("s3cr3t_token-1".to_owned()));
+        }));
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(found[0].contains("[webhook]"), "{:?}", found);
+
+        for secret in ["", "with space", "ümlaut", &"a".repeat(257)] {
+            let found = secret_problems(problems(|config| {
+                config.webhook = Some(webhook("https://example.org/"));
+                config.general.webhook_secret = Some(Secret::new(secret.to_owned()));
+            }));
+            assert_eq!(found.len(), 1, "{:?}: {:?}", secret, found);
+        }
+
+        let found = secret_problems(problems(|config| {
+            config.webhook = Some(webhook("https://example.org/"));
+            config.general.webhook_secret = Some(Secret::new("a".repeat(256)));
+        }));
+        assert!(found.is_empty(), "{:?}", found);
+    }
+
+    fn document(contents: &str) -> Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn unversioned_documents_are_stamped() {
+        let mut doc = document("[general]\nserver_port = 1\n");
+        let changes = migrate(&mut doc).unwrap();
+        assert_eq!(changes, ["config_version: 0 -> 1"]);
+        assert_eq!(document_version(&doc).unwrap(), CONFIG_VERSION);
+        assert_eq!(doc["general"]["server_port"].as_integer(), Some(1));
+    }
+
+    #[test]
+    fn current_documents_are_left_untouched() {
+        let mut doc = document("config_version = 1\n");
+        assert!(migrate(&mut doc).unwrap().is_empty());
+        assert_eq!(doc, document("config_version = 1\n"));
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        assert!(migrate(&mut document("config_version = 99\n")).is_err());
+        assert!(migrate(&mut document("config_version = -1\n")).is_err());
+        assert!(migrate(&mut document("config_version = 'one'\n")).is_err());
+    }
+
+    #[test]
+    fn version_stamps_keep_the_layout() {
+        let stamped = stamp_version("# Settings\nconfig_version = 0 \n\n[general]\n", 1);
+        assert_eq!(stamped, "# Settings\nconfig_version = 1\n\n[general]\n");
+
+        // Only the top-level key is the version of the file
+        let stamped = stamp_version("[general]\nconfig_version = 0\n", 1);
+        assert_eq!(stamped, "config_version = 1\n[general]\nconfig_version = 0\n");
+    }
+
+    #[test]
+    fn only_startup_writes_the_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let path = scratch_path("modes");
+        let backup = PathBuf::from(format!("{}.v0.bak", path.display()));
+        let original = "# Tuned by hand\n[general]\nserver_port = 9000 # behind the proxy\n";
+        std::fs::write(&path, original).unwrap();
+        let sources = ConfigSources {
+            path: path.clone(),
+            overrides: Vec::new(),
+        };
+
+        let (config, _) = load(&sources, LoadMode::ReadOnly, &logger).unwrap();
+        assert_eq!(config.general.server_port, 9000);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+        assert!(!backup.exists());
+
+        let (config, _) = load(&sources, LoadMode::Startup, &logger).unwrap();
+        assert_eq!(config.general.server_port, 9000);
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), original);
+        let upgraded = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(upgraded, format!("config_version = {}\n{}", CONFIG_VERSION, original));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    fn read_only_loads_require_the_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let sources = ConfigSources {
+            path: scratch_path("missing"),
+            overrides: Vec::new(),
+        };
+        let why = load(&sources, LoadMode::ReadOnly, &logger).unwrap_err();
+        assert!(why.to_string().ends_with("does not exist"), "{}", why);
+        assert!(!sources.path.exists());
+    }
+
 }
@@ -1,18 +1,24 @@
 use crate::config;
 use crate::prelude::*;
-use std::net::TcpListener;
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use telegram_bot_api::bot;
 use telegram_bot_api::bot::BotApi;
+use telegram_bot_api::methods::SetWebhook;
+use telegram_bot_api::types::WebhookInfo;
+use tokio::runtime::Handle;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use zeroize::Zeroizing;
 
-#[derive(Clone)]
+/// Secrets of the configuration are masked in the `Debug` output
+#[derive(Clone, Debug)]
 pub struct BootstrapRequirements {
     pub logger: slog::Logger,
     pub config: config::Config,
-    // + cli args
-    // + environment
+    pub config_sources: config::ConfigSources,
 }
 
 fn introduce_self(ctx: &BootstrapRequirements) {
@@ -26,24 +32,52 @@ fn introduce_self(ctx: &BootstrapRequirements) {
     debug!(ctx.logger, "Loaded config: {:#?}", ctx.config);
 }
 
-fn extract_token(ctx: &BootstrapRequirements) -> UResult<String> {
-    let token = std::env::var(&ctx.config.general.token_var);
-    if token.is_err() {
-        crit!(
-            ctx.logger,
-            "Could not fetch the API token from the environment"
-        );
-        Err(token.err().unwrap().into())
-    } else {
-        Ok(token.unwrap())
+/// Run the token helper and capture its standard output, its
+/// error output goes to ours
+async fn run_token_command(command: &[String]) -> UResult<Zeroizing<String>> {
+    let (program, args) = command.split_first().ok_or("The token command is empty")?;
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .await?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        return Err(format!("The token command exited with {}", output.status).into());
     }
+    let stdout = std::str::from_utf8(&stdout)?;
+    Ok(Zeroizing::new(stdout.to_owned()))
 }
 
-async fn instantiate_tgbot(ctx: &BootstrapRequirements) -> UResult<bot::BotApi> {
-    let token = extract_token(&ctx)?;
-    debug!(ctx.logger, "API token fetched");
+/// Read the api token from the configured source, the token is
+/// wiped from memory once dropped
+async fn extract_token(ctx: &BootstrapRequirements) -> UResult<Zeroizing<String>> {
+    let source = ctx.config.general.token_source();
+    let token = match source {
+        config::TokenSource::File(path) => std::fs::read_to_string(path)
+            .map(Zeroizing::new)
+            .map_err(|why| why.into()),
+        config::TokenSource::Command(command) => run_token_command(command).await,
+        config::TokenSource::Environment(var) => std::env::var(var)
+            .map(Zeroizing::new)
+            .map_err(|why| why.into()),
+    };
+    let token = token.and_then(|token| match token.trim() {
+        "" => Err("The token is empty".into()),
+        trimmed => Ok(Zeroizing::new(trimmed.to_owned())),
+    });
+    if let Err(ref why) = token {
+        crit!(ctx.logger, "Could not fetch the API token";
+            "source" => source.to_string(),
+            "reason" => why.to_string()
+        );
+    }
+    token
+}
 
-    let bot = bot::BotApi::new(token.into(), None).await;
+async fn instantiate_tgbot(ctx: &BootstrapRequirements, token: &str) -> UResult<bot::BotApi> {
+    let bot = bot::BotApi::new(token.to_owned(), None).await;
     match bot {
         Ok(v) => {
             info!(ctx.logger, "Telegram Bot instantiated");
@@ -53,7 +87,7 @@ async fn instantiate_tgbot(ctx: &BootstrapRequirements) -> UResult<bot::BotApi>
             crit!(
                 ctx.logger,
                 "Could not instantiate the bot with the provided token";
-                "env token" => &ctx.config.general.token_var,
+                "token source" => ctx.config.general.token_source().to_string(),
                 "reason" => format!("{:#?}", why)
             );
             Err("BotApi instantiation error".into())
@@ -61,12 +95,12 @@ async fn instantiate_tgbot(ctx: &BootstrapRequirements) -> UResult<bot::BotApi>
     }
 }
 
-async fn show_webhook_infos(ctx: &BootstrapRequirements, bot: &bot::BotApi) -> UResult {
+async fn show_webhook_infos(ctx: &BootstrapRequirements, bot: &bot::BotApi) -> UResult<WebhookInfo> {
     let infos = bot.get_webhook_info().await;
     match infos {
         Ok(infos) => {
             info!(ctx.logger, "Webhook status"; "infos" => format!("{:#?}", infos));
-            Ok(())
+            Ok(infos)
         }
         Err(_) => {
             crit!(ctx.logger, "Unable to get webhook infos!");
@@ -75,58 +109,160 @@ async fn show_webhook_infos(ctx: &BootstrapRequirements, bot: &bot::BotApi) -> U
     }
 }
 
-fn prepare_update_handler(ctx: &BootstrapRequirements) -> UResult<Arc<dyn UpdateHandler>> {
-    let builder = DefaultUpdateHandler::new().logger(ctx.logger.clone());
-    let integrations = ctx.config.integrations.as_ref();
-    if let None = integrations {
-        return Ok(Arc::new(builder.build()));
+/// Check whether the webhook registered on telegram's side
+/// matches the configured one
+fn webhook_outdated(ctx: &BootstrapRequirements, settings: &config::WebhookSection, infos: &WebhookInfo) -> bool {
+    let max_connections = settings.max_connections.map(|v| v as i64);
+    let allowed_updates = settings.allowed_updates.clone().unwrap_or_default();
+    infos.url != settings.url
+        || !infos.has_custom_certificate
+        || (max_connections.is_some() && infos.max_connections != max_connections)
+        || infos.allowed_updates.clone().unwrap_or_default() != allowed_updates
+        // Telegram never reveals the registered secret, it has
+        // to be registered again to be sure it is up to date
+        || ctx.config.general.webhook_secret.is_some()
+}
+
+/// Register the configured webhook along with the server's
+/// self-signed certificate if telegram has a different one
+async fn register_webhook(ctx: &BootstrapRequirements, bot: &bot::BotApi) -> UResult {
+    let infos = show_webhook_infos(ctx, bot).await?;
+    let settings = match ctx.config.webhook {
+        Some(ref settings) => settings,
+        None => return Ok(()),
+    };
+    if !webhook_outdated(ctx, settings, &infos) {
+        info!(ctx.logger, "Registered webhook is up to date");
+        return Ok(());
+    }
+
+    let request = {
+        let mut request = SetWebhook::new(settings.url.clone());
+        request.certificate = Some(load_input_file(&ctx.config.general.certificate_path)?);
+        request.max_connections = settings.max_connections.map(|v| v as i64);
+        request.allowed_updates = settings.allowed_updates.clone();
+        request.secret_token = ctx
+            .config
+            .general
+            .webhook_secret
+            .as_ref()
+            .map(|secret| secret.expose().clone());
+        request
+    };
+    match bot.set_webhook(request).await {
+        Ok(_) => {
+            info!(ctx.logger, "Webhook registered"; "url" => &settings.url);
+            Ok(())
+        }
+        Err(why) => {
+            crit!(
+                ctx.logger,
+                "Could not register the webhook";
+                "url" => &settings.url,
+                "reason" => format!("{:#?}", why)
+            );
+            Err("Webhook registration error".into())
+        }
     }
-    let integrations = integrations.unwrap();
-    let builder = if let Some(ref addr) = integrations.discord {
-        builder.discord_sender(Arc::new(CommandSender::new(
-            addr.to_string_lossy().into_owned(),
-        )))
+}
+
+/// Services shared by the update and command servers
+#[derive(Clone)]
+struct BridgeServices {
+    tgbot: Arc<BotApi>,
+    config: ConfigHandle,
+    store: Arc<dyn MessageStore>,
+    media: Option<Arc<MediaDownloader>>,
+}
+
+fn prepare_update_handler(
+    ctx: &BootstrapRequirements,
+    services: BridgeServices,
+) -> UResult<Arc<dyn UpdateHandler>> {
+    let builder = DefaultUpdateHandler::new()
+        .logger(ctx.logger.clone())
+        .bot(services.tgbot)
+        .config(services.config)
+        .store(services.store);
+    let builder = if let Some(media) = services.media {
+        builder.media_downloader(media)
     } else {
         builder
     };
     Ok(Arc::new(builder.build()))
 }
 
-fn bootstrap_update_server(ctx: &BootstrapRequirements) -> UResult {
+fn prepare_update_dispatcher(
+    ctx: &BootstrapRequirements,
+    services: BridgeServices,
+) -> UResult<Arc<dyn UpdateDispatcher>> {
+    let update_handler = prepare_update_handler(ctx, services)?;
+    Ok(Arc::new(DefaultUpdateDispatcher::new(
+        update_handler,
+        ctx.logger.clone(),
+    )))
+}
+
+fn prepare_update_poller(
+    ctx: &BootstrapRequirements,
+    services: BridgeServices,
+) -> UResult<UpdatePoller> {
+    let tgbot = services.tgbot.clone();
+    let update_dispatcher = prepare_update_dispatcher(ctx, services)?;
+    Ok(UpdatePoller::new()
+        .logger(ctx.logger.clone())
+        .bot(tgbot)
+        .dispatcher(update_dispatcher)
+        .offset_path(&ctx.config.polling.offset_path)
+        .timeout(ctx.config.polling.timeout)
+        .build())
+}
+
+async fn prepare_update_server(
+    ctx: &BootstrapRequirements,
+    services: BridgeServices,
+) -> UResult<UpdateServer> {
     let srv_addr = format!(
         "{}:{}",
         ctx.config.general.server_ip, ctx.config.general.server_port
     );
     let tls_config = create_server_config(&ctx.config)?;
+    let settings = &ctx.config.server;
 
-    let update_handler = prepare_update_handler(ctx)?;
-    let update_dispatcher = Arc::new(DefaultUpdateDispatcher::new(
-        update_handler,
-        ctx.logger.clone(),
-    ));
+    let update_dispatcher = prepare_update_dispatcher(ctx, services)?;
     let stream_handler = Arc::new(
         DefaultStreamHandler::new()
             .logger(ctx.logger.clone())
             .dispatcher(update_dispatcher.clone())
-            .tls_config(tls_config.clone())
+            .tls_config(tls_config)
+            .webhook_path(&ctx.config.general.webhook_path)
+            .secret_token(
+                ctx.config
+                    .general
+                    .webhook_secret
+                    .as_ref()
+                    .map(|secret| secret.expose().clone()),
+            )
+            .idle_timeout(Duration::from_secs(settings.idle_timeout))
+            .read_timeout(Duration::from_secs(settings.read_timeout))
+            .write_timeout(Duration::from_secs(settings.write_timeout))
+            .retry_after(Duration::from_secs(settings.retry_after))
             .build(),
     );
-    // let stream_listener = Arc::new(
-    // StreamListener::<TcpListener>::new()
-    // .logger(ctx.logger.clone())
-    // .listener(TcpListener::bind(srv_addr)?)
-    // .stream_handler(stream_handler)
-    // .build(),
-    // );
-    let update_server = UpdateServer::new()
+    UpdateServer::new()
         .logger(ctx.logger.clone())
         .server_addr(&srv_addr)
         .stream_handler(stream_handler)
-        .build()?;
-    update_server.listen()
+        .max_connections(settings.max_connections)
+        .build()
+        .await
 }
 
-fn bootstrap_command_server(ctx: &BootstrapRequirements, tgbot: Arc<BotApi>) -> UResult {
+fn prepare_command_server(
+    ctx: &BootstrapRequirements,
+    services: BridgeServices,
+    runtime: Handle,
+) -> UResult<CommandServer> {
     let srv_addr = format!(
         "{}",
         ctx.config.general.sock_addr.to_string_lossy().into_owned()
@@ -135,8 +271,10 @@ fn bootstrap_command_server(ctx: &BootstrapRequirements, tgbot: Arc<BotApi>) ->
     let command_handler = Arc::new(
         AppCommandHandler::new()
             .logger(ctx.logger.clone())
-            .bot(tgbot.clone())
-            .runtime(tokio::runtime::Runtime::new()?)
+            .bot(services.tgbot)
+            .config(services.config)
+            .store(services.store)
+            .runtime(runtime)
             .build()
     );
     let command_dispatcher = Arc::new(DefaultCommandDispatcher::new(
@@ -147,53 +285,311 @@ fn bootstrap_command_server(ctx: &BootstrapRequirements, tgbot: Arc<BotApi>) ->
         command_dispatcher,
         ctx.logger.clone(),
     ));
-    let update_server = CommandServer::new()
+    CommandServer::new()
         .logger(ctx.logger.clone())
         .server_addr(&srv_addr)
         .stream_handler(stream_handler)
-        .build()?;
-    update_server.listen()
+        .build()
+}
+
+/// Source of telegram updates selected by the configuration
+enum UpdateSource {
+    Server(UpdateServer),
+    Poller(UpdatePoller),
+}
+
+impl UpdateSource {
+    async fn listen(&self) -> UResult {
+        match self {
+            UpdateSource::Server(server) => server.listen().await,
+            UpdateSource::Poller(poller) => poller.listen().await,
+        }
+    }
+
+    /// Wait for the connections still being served, the
+    /// poller is done as soon as its listening loop ends
+    async fn drain(&self) {
+        if let UpdateSource::Server(server) = self {
+            server.drain().await;
+        }
+    }
+}
+
+async fn prepare_update_source(
+    ctx: &BootstrapRequirements,
+    services: BridgeServices,
+) -> UResult<Arc<UpdateSource>> {
+    let source = match ctx.config.general.mode {
+        config::UpdateMode::Webhook => {
+            UpdateSource::Server(prepare_update_server(ctx, services).await?)
+        }
+        config::UpdateMode::Polling => UpdateSource::Poller(prepare_update_poller(ctx, services)?),
+    };
+    Ok(Arc::new(source))
+}
+
+impl Supervisable for UpdateSource {
+    fn request_stop(&self) {
+        match self {
+            UpdateSource::Server(server) => server.request_stop(),
+            UpdateSource::Poller(poller) => poller.request_stop(),
+        }
+    }
+}
+
+impl Supervisable for CommandServer {
+    fn request_stop(&self) {
+        StreamListenerExt::<UnixListener>::request_stop(self)
+    }
+}
+
+/// Run the blocking command server on a detached thread,
+/// which cannot hold the exit back
+async fn listen_detached(server: Arc<CommandServer>) -> UResult {
+    let (result_tx, result) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = result_tx.send(server.listen());
+    });
+    result.await?
+}
+
+fn restart_policy(ctx: &BootstrapRequirements) -> RestartPolicy {
+    let settings = &ctx.config.supervisor;
+    RestartPolicy {
+        max_restarts: settings.max_restarts,
+        initial_backoff: Duration::from_secs(settings.initial_backoff),
+        max_backoff: Duration::from_secs(settings.max_backoff),
+    }
+}
+
+/// Log the end of a supervisor which gave up on its server,
+/// which brings the whole application down
+fn supervisor_ended(ctx: &BootstrapRequirements, server: &str, result: UResult) -> UResult {
+    let why = match result {
+        Ok(()) => format!("The {} supervisor ended unexpectedly", server).into(),
+        Err(why) => why,
+    };
+    crit!(ctx.logger, "Shutting down, the {} could not be kept running", server;
+        "reason" => format!("{}", why)
+    );
+    Err(why)
+}
+
+/// Remove the socket file of the command server so that the
+/// next start could bind it again
+fn remove_socket_file(ctx: &BootstrapRequirements) {
+    let path = &ctx.config.general.sock_addr;
+    match std::fs::remove_file(path) {
+        Ok(_) => (),
+        Err(why) if why.kind() == std::io::ErrorKind::NotFound => (),
+        Err(why) => warn!(ctx.logger, "Could not remove the command socket: {:#?}", why;
+            "path" => path.to_string_lossy().into_owned()
+        ),
+    }
+}
+
+/// Read the configuration file again and apply its bridge
+/// routes and integrations, the other settings are only
+/// reported as waiting for a restart
+fn reload_config(ctx: &BootstrapRequirements, handle: &ConfigHandle) -> UResult {
+    let (new_config, _) = config::load(&ctx.config_sources, config::LoadMode::ReadOnly, &ctx.logger)?;
+    config::validate(&new_config)?;
+
+    let pending = restart_required(&ctx.config, &new_config);
+    if !pending.is_empty() {
+        warn!(ctx.logger, "Some changed settings require a restart to take effect";
+            "settings" => pending.join(", ")
+        );
+    }
+    if new_config.bridge.is_empty() {
+        warn!(ctx.logger, "The bridge routing table is empty, no message will be forwarded");
+    }
+    if let Ok(dump) = redacted(&new_config) {
+        debug!(ctx.logger, "Reloaded config"; "config" => dump.to_string());
+    }
+    let routes = new_config.bridge.len();
+    handle.replace(new_config);
+    info!(ctx.logger, "Configuration reloaded";
+        "path" => ctx.config_sources.path.to_string_lossy().into_owned(),
+        "routes" => routes
+    );
+    Ok(())
+}
+
+/// Reload the configuration on every SIGHUP, a faulty file
+/// leaves the live configuration untouched
+async fn watch_reloads(ctx: BootstrapRequirements, handle: ConfigHandle) -> UResult {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!(ctx.logger, "Received SIGHUP, reloading the configuration");
+        if let Err(why) = reload_config(&ctx, &handle) {
+            error!(ctx.logger, "Could not reload the configuration, keeping the current one";
+                "reason" => format!("{}", why)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn open_message_store(ctx: &BootstrapRequirements) -> UResult<Arc<dyn MessageStore>> {
+    let settings = &ctx.config.storage;
+    let retention = Duration::from_secs(settings.retention_days as u64 * 24 * 60 * 60);
+    match LogMessageStore::open(&settings.path, retention, ctx.logger.clone()) {
+        Ok(store) => Ok(Arc::new(store)),
+        Err(why) => {
+            crit!(
+                ctx.logger,
+                "Could not open the message log";
+                "path" => settings.path.to_string_lossy().into_owned(),
+                "reason" => format!("{:#?}", why)
+            );
+            Err(why)
+        }
+    }
+}
+
+fn prepare_media_downloader(
+    ctx: &BootstrapRequirements,
+    tgbot: Arc<BotApi>,
+    token: &str,
+) -> Option<Arc<MediaDownloader>> {
+    let download_dir = &ctx.config.media.download_dir;
+    match MediaDownloader::new(tgbot, token, download_dir, ctx.logger.clone()) {
+        Ok(downloader) => Some(Arc::new(downloader)),
+        Err(why) => {
+            error!(
+                ctx.logger,
+                "Could not prepare the media download directory, attachments won't be forwarded";
+                "path" => download_dir.to_string_lossy().into_owned(),
+                "reason" => format!("{:#?}", why)
+            );
+            None
+        }
+    }
 }
 
 pub async fn bootstrap(ctx: BootstrapRequirements) -> UResult {
     introduce_self(&ctx);
+    if let Err(why) = config::validate(&ctx.config) {
+        crit!(ctx.logger, "Invalid configuration: {}", why);
+        return Err(why.into());
+    }
 
-    let bot_fut = instantiate_tgbot(&ctx);
-
-    let bot = bot_fut.await?;
-    show_webhook_infos(&ctx, &bot).await?;
-    let bot = Arc::new(bot);
-    
-
-    thread::scope(|scope| -> UResult {
-        scope.spawn(|| -> UResult {
-            if let Err(why) = bootstrap_update_server(&ctx) {
-                crit!(
-                    ctx.logger,
-                    "An error occured while running the update server: {:#?}",
-                    why
-                );
-                Err(why.into())
-            } else {
-                Ok(())
-            }
-        });
-
-        scope.spawn(|| -> UResult {
-            if let Err(why) = bootstrap_command_server(&ctx, bot.clone()) {
-                crit!(
-                    ctx.logger,
-                    "An error occured while running the command server: {:#?}",
-                    why
-                );
-                Err(why.into())
-            } else {
-                Ok(())
-            }
-        });
-
-        Ok(())
-    })?;
+    let token = extract_token(&ctx).await?;
+    debug!(ctx.logger, "API token fetched";
+        "source" => ctx.config.general.token_source().to_string()
+    );
+    let bot = Arc::new(instantiate_tgbot(&ctx, &token).await?);
+    let media = prepare_media_downloader(&ctx, bot.clone(), &token);
+    // The bot and the media downloader hold their own copy,
+    // ours gets wiped
+    drop(token);
+    if let Some(ref media) = media {
+        tokio::spawn(media.clone().purge_periodically());
+    }
 
-    Ok(())
+    match ctx.config.general.mode {
+        config::UpdateMode::Webhook => register_webhook(&ctx, &bot).await?,
+        config::UpdateMode::Polling => (),
+    };
+    let services = BridgeServices {
+        tgbot: bot.clone(),
+        config: ConfigHandle::new(ctx.config.clone()),
+        store: open_message_store(&ctx)?,
+        media,
+    };
+    if ctx.config.bridge.is_empty() {
+        warn!(ctx.logger, "The bridge routing table is empty, no message will be forwarded");
+    }
+    tokio::spawn(watch_reloads(ctx.clone(), services.config.clone()));
+
+    let update_supervisor = Arc::new(Supervisor::<UpdateSource>::new(
+        "update server",
+        restart_policy(&ctx),
+        ctx.logger.clone(),
+    ));
+    let command_supervisor = Arc::new(Supervisor::<CommandServer>::new(
+        "command server",
+        restart_policy(&ctx),
+        ctx.logger.clone(),
+    ));
+
+    let mut updates = {
+        let supervisor = update_supervisor.clone();
+        let ctx = ctx.clone();
+        let services = services.clone();
+        tokio::spawn(async move {
+            let start = || prepare_update_source(&ctx, services.clone());
+            supervisor
+                .run(start, |source: Arc<UpdateSource>| async move { source.listen().await })
+                .await
+        })
+    };
+    let mut commands = {
+        let supervisor = command_supervisor.clone();
+        let ctx = ctx.clone();
+        let services = services.clone();
+        let runtime = Handle::current();
+        tokio::spawn(async move {
+            let start = || async {
+                // A socket file left by a failed instance prevents
+                // the new one from binding
+                remove_socket_file(&ctx);
+                prepare_command_server(&ctx, services.clone(), runtime.clone()).map(Arc::new)
+            };
+            supervisor.run(start, listen_detached).await
+        })
+    };
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let (mut updates_done, mut commands_done) = (false, false);
+    let outcome = tokio::select! {
+        _ = terminate.recv() => {
+            info!(ctx.logger, "Received SIGTERM, shutting down");
+            Ok(())
+        }
+        _ = tokio::signal::ctrl_c() => {
+            info!(ctx.logger, "Received SIGINT, shutting down");
+            Ok(())
+        }
+        result = &mut updates => {
+            updates_done = true;
+            supervisor_ended(&ctx, "update server", result.unwrap_or_else(|why| Err(why.into())))
+        }
+        result = &mut commands => {
+            commands_done = true;
+            supervisor_ended(&ctx, "command server", result.unwrap_or_else(|why| Err(why.into())))
+        }
+    };
+
+    update_supervisor.request_stop();
+    command_supervisor.request_stop();
+    // The command server only notices the stop request once
+    // it accepts a connection
+    let _ = UnixStream::connect(&ctx.config.general.sock_addr);
+
+    let drain = async {
+        if !updates_done {
+            let _ = updates.await;
+        }
+        if let Some(source) = update_supervisor.current() {
+            source.drain().await;
+        }
+        if !commands_done {
+            let _ = commands.await;
+        }
+    };
+    let deadline = Duration::from_secs(ctx.config.general.shutdown_timeout);
+    let drained = tokio::time::timeout(deadline, drain).await.is_ok();
+    remove_socket_file(&ctx);
+
+    if drained {
+        info!(ctx.logger, "Shutdown complete");
+        outcome
+    } else {
+        error!(ctx.logger, "Shutdown deadline exceeded, abandoning the ongoing work";
+            "deadline_secs" => deadline.as_secs()
+        );
+        outcome.and(Err("Shutdown deadline exceeded".into()))
+    }
 }
@@ -1,13 +1,91 @@
+use async_trait::async_trait;
 use serde::Serialize;
 use slog::Logger;
 
 use crate::prelude::*;
 
-use telegram_bot_api::types::Message;
+use telegram_bot_api::types::{
+    CallbackQuery, ChatJoinRequest, ChatMemberUpdated, InlineQuery, Message, Update,
+};
 
 /// An interface for handling dispatched telegram
 /// updates
+///
+/// Only `message` is mandatory, all the other kinds
+/// of updates are ignored unless the handler overrides
+/// the corresponding method
+#[async_trait]
 pub trait UpdateHandler: Send + Sync {
     /// Process a message received by the telegram bot
-    fn message(&self, _msg: Message) -> UResult;
+    async fn message(&self, _msg: Message) -> UResult;
+
+    /// Process a new version of a message that is known
+    /// to the bot and was edited
+    async fn edited_message(&self, _msg: Message) -> UResult {
+        Ok(())
+    }
+
+    /// Process a new incoming channel post
+    async fn channel_post(&self, _msg: Message) -> UResult {
+        Ok(())
+    }
+
+    /// Process a new version of a channel post that is
+    /// known to the bot and was edited
+    async fn edited_channel_post(&self, _msg: Message) -> UResult {
+        Ok(())
+    }
+
+    /// Process a new incoming callback query
+    async fn callback_query(&self, _query: CallbackQuery) -> UResult {
+        Ok(())
+    }
+
+    /// Process a new incoming inline query
+    async fn inline_query(&self, _query: InlineQuery) -> UResult {
+        Ok(())
+    }
+
+    /// Process a change of a chat member's status
+    async fn chat_member(&self, _update: ChatMemberUpdated) -> UResult {
+        Ok(())
+    }
+
+    /// Process a change of the bot's own member status
+    /// in a chat
+    async fn my_chat_member(&self, _update: ChatMemberUpdated) -> UResult {
+        Ok(())
+    }
+
+    /// Process a request to join a chat administered
+    /// by the bot
+    async fn chat_join_request(&self, _request: ChatJoinRequest) -> UResult {
+        Ok(())
+    }
+}
+
+/// An interface for dispatching telegram updates to
+/// the corresponding methods of an update handler
+#[async_trait]
+pub trait UpdateDispatcher: Send + Sync {
+    /// Route the update to the handler
+    async fn dispatch(&self, update: Update) -> UResult;
+}
+
+/// An interface for serving the connections accepted
+/// by an asynchronous server
+#[async_trait]
+pub trait AsyncStreamHandler<S: Send>: Send + Sync {
+    /// Serve the connection until it gets closed
+    async fn handle_stream(&self, stream: S) -> UResult;
+
+    /// Stop keeping the served connections alive, the ongoing
+    /// requests are still completed
+    fn request_stop(&self) {}
+
+    /// Refuse a connection the server has no capacity for,
+    /// the connection is simply dropped by default
+    async fn reject_stream(&self, _stream: S) -> UResult {
+        Ok(())
+    }
 }
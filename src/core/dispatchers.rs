@@ -1,3 +1,4 @@
+use async_trait::async_trait;
 use slog::Logger;
 use telegram_bot_api::types::Update;
 
@@ -20,11 +21,33 @@ impl DefaultUpdateDispatcher {
     }
 }
 
-impl Dispatcher<Update> for DefaultUpdateDispatcher {
-    fn dispatch(&self, data: Update) -> UResult {
+#[async_trait]
+impl UpdateDispatcher for DefaultUpdateDispatcher {
+    async fn dispatch(&self, data: Update) -> UResult {
+        let update_id = data.update_id;
         if let Some(msg) = data.message {
-            self.handler.message(msg)?;
+            self.handler.message(msg).await
+        } else if let Some(msg) = data.edited_message {
+            self.handler.edited_message(msg).await
+        } else if let Some(msg) = data.channel_post {
+            self.handler.channel_post(msg).await
+        } else if let Some(msg) = data.edited_channel_post {
+            self.handler.edited_channel_post(msg).await
+        } else if let Some(query) = data.callback_query {
+            self.handler.callback_query(query).await
+        } else if let Some(query) = data.inline_query {
+            self.handler.inline_query(query).await
+        } else if let Some(update) = data.chat_member {
+            self.handler.chat_member(update).await
+        } else if let Some(update) = data.my_chat_member {
+            self.handler.my_chat_member(update).await
+        } else if let Some(request) = data.chat_join_request {
+            self.handler.chat_join_request(request).await
+        } else {
+            debug!(self.logger, "Received an update of an unsupported kind, skipping";
+                "update_id" => update_id
+            );
+            Ok(())
         }
-        Ok(())
     }
 }
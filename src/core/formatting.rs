@@ -0,0 +1,780 @@
+use telegram_bot_api::types::{MessageEntity, MessageEntityType};
+
+/// Kind of formatting applied to a range of text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanKind {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Spoiler,
+    Code,
+    Pre(Option<String>),
+    TextLink(String),
+}
+
+/// Formatted range of a text, offsets and lengths are counted
+/// in UTF-16 code units as telegram expects them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub kind: SpanKind,
+    pub offset: i64,
+    pub length: i64,
+}
+
+impl Span {
+    /// Convert the span into a telegram entity, moving it by the
+    /// given number of UTF-16 code units
+    pub fn to_entity(&self, shift: i64) -> MessageEntity {
+        let (offset, length) = (self.offset + shift, self.length);
+        match self.kind {
+            SpanKind::Bold => MessageEntity::new_bold(offset, length),
+            SpanKind::Italic => MessageEntity::new_italic(offset, length),
+            SpanKind::Underline => MessageEntity::new_underline(offset, length),
+            SpanKind::Strikethrough => MessageEntity::new_strikethrough(offset, length),
+            SpanKind::Spoiler => MessageEntity::new_spoiler(offset, length),
+            SpanKind::Code => MessageEntity::new_code(offset, length),
+            SpanKind::Pre(ref language) => MessageEntity::new_pre(offset, length, language.clone()),
+            SpanKind::TextLink(ref url) => MessageEntity::new_text_link(offset, length, url.clone()),
+        }
+    }
+}
+
+/// Plain text along with its formatting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormattedText {
+    pub text: String,
+    pub spans: Vec<Span>,
+}
+
+impl FormattedText {
+    /// Telegram entities of the text placed after a prefix of
+    /// the given length in UTF-16 code units
+    pub fn entities(&self, shift: i64) -> Vec<MessageEntity> {
+        self.spans.iter().map(|s| s.to_entity(shift)).collect()
+    }
+}
+
+/// Characters which can be escaped with a backslash in
+/// discord markdown
+const DISCORD_ESCAPABLE: &str = "\\*_~|`>[]()<#-";
+
+/// Prefix replacing the discord block quote marker, telegram
+/// has no block quote entity so quotes are rendered in italic
+/// behind a vertical bar
+const QUOTE_BAR: &str = "┃ ";
+
+struct DiscordParser<'a> {
+    chars: &'a [char],
+    out: String,
+    out_len: i64,
+    spans: Vec<Span>,
+}
+
+/// Length of the run of the same character starting at `i`
+fn run_length(chars: &[char], i: usize) -> usize {
+    chars[i..].iter().take_while(|&&c| c == chars[i]).count()
+}
+
+impl<'a> DiscordParser<'a> {
+    fn push(&mut self, c: char) {
+        self.out.push(c);
+        self.out_len += c.len_utf16() as i64;
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.push(c);
+        }
+    }
+
+    fn push_raw(&mut self, chars: &[char]) {
+        for &c in chars {
+            self.push(c);
+        }
+    }
+
+    fn is_line_start(&self, i: usize) -> bool {
+        i == 0 || self.chars[i - 1] == '\n'
+    }
+
+    /// Find the closing run of exactly `len` characters `c`
+    /// in `from..to`, skipping escaped characters
+    fn find_closing(&self, c: char, len: usize, from: usize, to: usize) -> Option<usize> {
+        let mut i = from;
+        while i < to {
+            if self.chars[i] == '\\' {
+                i += 2;
+                continue;
+            }
+            if self.chars[i] == c {
+                let run = run_length(&self.chars[..to], i);
+                if run == len {
+                    return Some(i);
+                }
+                i += run;
+                continue;
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Find a sequence of characters in `from..to`
+    fn find_seq(&self, seq: &str, from: usize, to: usize) -> Option<usize> {
+        let seq: Vec<char> = seq.chars().collect();
+        (from..to.saturating_sub(seq.len() - 1)).find(|&i| self.chars[i..i + seq.len()] == seq[..])
+    }
+
+    /// Parse the range as formatted text and register the spans
+    /// covering its output
+    fn wrap(&mut self, kinds: &[SpanKind], from: usize, to: usize) {
+        let offset = self.out_len;
+        self.parse(from, to);
+        let length = self.out_len - offset;
+        if length > 0 {
+            for kind in kinds {
+                self.spans.push(Span {
+                    kind: kind.clone(),
+                    offset,
+                    length,
+                });
+            }
+        }
+    }
+
+    /// Try to parse an emphasis delimited by runs of `c` at `i`,
+    /// returns the position after the closing delimiter
+    fn emphasis(&mut self, c: char, i: usize, to: usize) -> Option<usize> {
+        let run = run_length(&self.chars[..to], i).min(3);
+        let kinds = match (c, run) {
+            ('*', 1) | ('_', 1) => vec![SpanKind::Italic],
+            ('*', 2) => vec![SpanKind::Bold],
+            ('*', 3) => vec![SpanKind::Bold, SpanKind::Italic],
+            ('_', 2) => vec![SpanKind::Underline],
+            ('_', 3) => vec![SpanKind::Underline, SpanKind::Italic],
+            ('~', 2) => vec![SpanKind::Strikethrough],
+            ('|', 2) => vec![SpanKind::Spoiler],
+            _ => return None,
+        };
+        let start = i + run;
+        let end = self.find_closing(c, run, start, to)?;
+        if end == start || self.chars[start].is_whitespace() {
+            return None;
+        }
+        // Single underscores inside words are part of identifiers
+        if c == '_' && run == 1 {
+            let before = i > 0 && self.chars[i - 1].is_alphanumeric();
+            let after = end + 1 < self.chars.len() && self.chars[end + 1].is_alphanumeric();
+            if before || after {
+                return None;
+            }
+        }
+        self.wrap(&kinds, start, end);
+        Some(end + run)
+    }
+
+    /// Try to parse a code block or an inline code span at `i`
+    fn code(&mut self, i: usize, to: usize) -> Option<usize> {
+        let run = run_length(&self.chars[..to], i);
+        if run >= 3 {
+            let start = i + 3;
+            let end = self.find_seq("```", start, to)?;
+            let chars = self.chars;
+            let body = &chars[start..end];
+            // The first line holds the language if it is a single word
+            let (language, body) = match body.iter().position(|&c| c == '\n') {
+                Some(nl) if nl > 0 && body[..nl].iter().all(|c| c.is_alphanumeric() || "+-#_".contains(*c)) => {
+                    (Some(body[..nl].iter().collect::<String>()), &body[nl + 1..])
+                }
+                Some(0) => (None, &body[1..]),
+                _ => (None, body),
+            };
+            let body = match body.last() {
+                Some('\n') => &body[..body.len() - 1],
+                _ => body,
+            };
+            if body.is_empty() {
+                return None;
+            }
+            let offset = self.out_len;
+            self.push_raw(body);
+            self.spans.push(Span {
+                kind: SpanKind::Pre(language),
+                offset,
+                length: self.out_len - offset,
+            });
+            return Some(end + 3);
+        }
+
+        let start = i + run;
+        let end = (start..to).find(|&j| self.chars[j] == '`' && run_length(&self.chars[..to], j) == run)?;
+        if end == start {
+            return None;
+        }
+        let chars = self.chars;
+        let offset = self.out_len;
+        self.push_raw(&chars[start..end]);
+        self.spans.push(Span {
+            kind: SpanKind::Code,
+            offset,
+            length: self.out_len - offset,
+        });
+        Some(end + run)
+    }
+
+    /// Try to parse a masked link `[text](url)` at `i`
+    fn masked_link(&mut self, i: usize, to: usize) -> Option<usize> {
+        let text_end = self.find_closing(']', 1, i + 1, to)?;
+        if text_end + 1 >= to || self.chars[text_end + 1] != '(' {
+            return None;
+        }
+        let url_start = text_end + 2;
+        // Urls may contain balanced parentheses, like wikipedia ones
+        let mut depth = 0;
+        let url_end = (url_start..to).find(|&j| {
+            match self.chars[j] {
+                '(' => depth += 1,
+                ')' if depth == 0 => return true,
+                ')' => depth -= 1,
+                _ => (),
+            }
+            false
+        })?;
+        let url: String = self.chars[url_start..url_end].iter().collect();
+        let url = url.trim().trim_start_matches('<').trim_end_matches('>').to_owned();
+        if !(url.starts_with("https://") || url.starts_with("http://")) {
+            return None;
+        }
+        self.wrap(&[SpanKind::TextLink(url)], i + 1, text_end);
+        Some(url_end + 1)
+    }
+
+    /// Try to parse a link wrapped in angle brackets to suppress
+    /// its embed, only the link itself is kept
+    fn bracketed_link(&mut self, i: usize, to: usize) -> Option<usize> {
+        let end = (i + 1..to).find(|&j| self.chars[j] == '>' || self.chars[j].is_whitespace())?;
+        if self.chars[end] != '>' {
+            return None;
+        }
+        let url: String = self.chars[i + 1..end].iter().collect();
+        if !(url.starts_with("https://") || url.starts_with("http://")) {
+            return None;
+        }
+        self.push_str(&url);
+        Some(end + 1)
+    }
+
+    /// Try to parse a block quote at the start of a line at `i`,
+    /// either a single line `> ` or the rest of the text `>>> `
+    fn quote(&mut self, i: usize, to: usize) -> Option<usize> {
+        let rest = &self.chars[i..to];
+        let (marker, multiline) = if rest.starts_with(&['>', '>', '>', ' ']) {
+            (4, true)
+        } else if rest.starts_with(&['>', ' ']) {
+            (2, false)
+        } else {
+            return None;
+        };
+
+        let mut line_start = i + marker;
+        loop {
+            let line_end = (line_start..to).find(|&j| self.chars[j] == '\n').unwrap_or(to);
+            self.push_str(QUOTE_BAR);
+            self.wrap(&[SpanKind::Italic], line_start, line_end);
+            if !multiline || line_end == to {
+                return Some(line_end);
+            }
+            self.push('\n');
+            line_start = line_end + 1;
+        }
+    }
+
+    fn parse(&mut self, from: usize, to: usize) {
+        let chars = self.chars;
+        let mut i = from;
+        while i < to {
+            let c = chars[i];
+            let next = match c {
+                '\\' if i + 1 < to && DISCORD_ESCAPABLE.contains(chars[i + 1]) => {
+                    self.push(chars[i + 1]);
+                    Some(i + 2)
+                }
+                '`' => self.code(i, to),
+                '*' | '_' | '~' | '|' => self.emphasis(c, i, to),
+                '[' => self.masked_link(i, to),
+                '<' => self.bracketed_link(i, to),
+                '>' if self.is_line_start(i) => self.quote(i, to),
+                _ => None,
+            };
+            match next {
+                Some(next) => i = next,
+                None => {
+                    // Not a formatting construct, the whole run of the
+                    // character is kept as is
+                    let run = if "*_~|`".contains(c) {
+                        run_length(&chars[..to], i)
+                    } else {
+                        1
+                    };
+                    self.push_raw(&chars[i..i + run]);
+                    i += run;
+                }
+            }
+        }
+    }
+}
+
+/// Convert discord markdown into plain text with formatting
+/// spans suitable for telegram entities
+pub fn discord_to_telegram(markdown: &str) -> FormattedText {
+    let chars: Vec<char> = markdown.chars().collect();
+    let mut parser = DiscordParser {
+        chars: &chars,
+        out: String::with_capacity(markdown.len()),
+        out_len: 0,
+        spans: Vec::new(),
+    };
+    parser.parse(0, chars.len());
+
+    let mut spans = parser.spans;
+    spans.sort_by(|a, b| a.offset.cmp(&b.offset).then(b.length.cmp(&a.length)));
+    FormattedText {
+        text: parser.out,
+        spans,
+    }
+}
+
+/// Characters with a special meaning anywhere in discord markdown
+const DISCORD_SPECIAL: &str = "\\*_~|`";
+
+/// Characters with a special meaning at the start of a line in
+/// discord markdown
+const DISCORD_LINE_SPECIAL: &str = ">#-";
+
+/// Escape the discord markdown characters of a plain text
+///
+/// `line_start` tells whether the text begins a new line in the
+/// resulting message
+pub fn escape_discord(text: &str, line_start: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut at_line_start = line_start;
+    for c in text.chars() {
+        if DISCORD_SPECIAL.contains(c) || (at_line_start && DISCORD_LINE_SPECIAL.contains(c)) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        at_line_start = c == '\n';
+    }
+    escaped
+}
+
+/// Discord rendering of a telegram entity
+enum Markup {
+    /// Text wrapped between a pair of markers
+    Wrap(&'static str),
+    InlineCode,
+    CodeBlock(Option<String>),
+    Link(String),
+    /// Text which must be kept as is, like urls and emails
+    Verbatim,
+}
+
+struct TelegramEntity {
+    markup: Markup,
+    start: usize,
+    end: usize,
+}
+
+impl TelegramEntity {
+    fn new(entity: &MessageEntity) -> Option<Self> {
+        let markup = match entity.type_field {
+            MessageEntityType::Bold => Markup::Wrap("**"),
+            MessageEntityType::Italic => Markup::Wrap("*"),
+            MessageEntityType::Underline => Markup::Wrap("__"),
+            MessageEntityType::Strikethrough => Markup::Wrap("~~"),
+            MessageEntityType::Spoiler => Markup::Wrap("||"),
+            // Telegram users can't be mentioned on discord, the name
+            // is highlighted instead
+            MessageEntityType::TextMention => Markup::Wrap("**"),
+            MessageEntityType::Code => Markup::InlineCode,
+            MessageEntityType::Pre => Markup::CodeBlock(entity.language.clone()),
+            MessageEntityType::TextLink => Markup::Link(entity.url.clone()?),
+            MessageEntityType::Url | MessageEntityType::Email => Markup::Verbatim,
+            _ => return None,
+        };
+        let start = usize::try_from(entity.offset).ok()?;
+        let end = start + usize::try_from(entity.length).ok()?;
+        Some(Self { markup, start, end })
+    }
+}
+
+struct DiscordRenderer<'a> {
+    text: &'a [u16],
+    entities: Vec<TelegramEntity>,
+}
+
+impl<'a> DiscordRenderer<'a> {
+    fn slice(&self, from: usize, to: usize) -> String {
+        String::from_utf16_lossy(&self.text[from..to])
+    }
+
+    fn is_line_start(&self, i: usize) -> bool {
+        i == 0 || self.text[i - 1] == '\n' as u16
+    }
+
+    /// Render the text in `from..to` with the entities starting
+    /// from the `first` one which are contained in this range
+    fn render(&self, from: usize, to: usize, first: usize) -> String {
+        let mut out = String::new();
+        let mut i = from;
+        let mut e = first;
+        while e < self.entities.len() && self.entities[e].start < to {
+            let entity = &self.entities[e];
+            // Partially overlapping entities are dropped
+            if entity.start < i || entity.end > to {
+                e += 1;
+                continue;
+            }
+            out.push_str(&escape_discord(&self.slice(i, entity.start), self.is_line_start(i)));
+
+            // Entities nested in the current one directly follow it
+            let mut next = e + 1;
+            while next < self.entities.len() && self.entities[next].start < entity.end {
+                next += 1;
+            }
+            out.push_str(&self.render_entity(entity, e + 1));
+            i = entity.end;
+            e = next;
+        }
+        out.push_str(&escape_discord(&self.slice(i, to), self.is_line_start(i)));
+        out
+    }
+
+    fn render_entity(&self, entity: &TelegramEntity, first_nested: usize) -> String {
+        match entity.markup {
+            Markup::Wrap(marker) => {
+                let inner = self.render(entity.start, entity.end, first_nested);
+                // Discord ignores markers touching whitespace, it is
+                // moved outside of them
+                let trimmed = inner.trim();
+                if trimmed.is_empty() {
+                    return inner;
+                }
+                let leading = &inner[..inner.len() - inner.trim_start().len()];
+                let trailing = &inner[inner.trim_end().len()..];
+                format!("{}{}{}{}{}", leading, marker, trimmed, marker, trailing)
+            }
+            Markup::InlineCode => {
+                let code = self.slice(entity.start, entity.end);
+                if code.contains('`') {
+                    format!("`` {} ``", code)
+                } else {
+                    format!("`{}`", code)
+                }
+            }
+            Markup::CodeBlock(ref language) => {
+                let code = self.slice(entity.start, entity.end).replace("```", "`\u{200b}``");
+                format!("```{}\n{}\n```", language.as_deref().unwrap_or_default(), code)
+            }
+            Markup::Link(ref url) => {
+                let inner = self.render(entity.start, entity.end, first_nested);
+                format!("[{}]({})", inner, link_target(url))
+            }
+            Markup::Verbatim => self.slice(entity.start, entity.end),
+        }
+    }
+}
+
+/// Url of a masked link, unbalanced parentheses would end the
+/// link early and are percent-encoded
+fn link_target(url: &str) -> String {
+    let mut depth = 0i32;
+    for c in url.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+        if depth < 0 {
+            break;
+        }
+    }
+    if depth == 0 {
+        url.to_owned()
+    } else {
+        url.replace('(', "%28").replace(')', "%29")
+    }
+}
+
+/// Check whether the UTF-16 offset falls between two characters
+/// rather than inside of a surrogate pair
+fn is_char_boundary(text: &[u16], i: usize) -> bool {
+    i == 0 || i >= text.len() || !(0xDC00..=0xDFFF).contains(&text[i])
+}
+
+/// Convert a telegram text with its entities into discord markdown,
+/// characters of the plain text which would be interpreted as
+/// markdown are escaped
+pub fn telegram_to_discord(text: &str, entities: &[MessageEntity]) -> String {
+    let text: Vec<u16> = text.encode_utf16().collect();
+    let mut entities: Vec<TelegramEntity> = entities
+        .iter()
+        .filter_map(TelegramEntity::new)
+        .filter(|e| e.start < e.end && e.end <= text.len())
+        .filter(|e| is_char_boundary(&text, e.start) && is_char_boundary(&text, e.end))
+        .collect();
+    // Outer entities go before the ones nested in them
+    entities.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+
+    let renderer = DiscordRenderer {
+        text: &text,
+        entities,
+    };
+    renderer.render(0, text.len(), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(kind: SpanKind, offset: i64, length: i64) -> Span {
+        Span {
+            kind,
+            offset,
+            length,
+        }
+    }
+
+    fn parsed(markdown: &str) -> (String, Vec<Span>) {
+        let formatted = discord_to_telegram(markdown);
+        (formatted.text, formatted.spans)
+    }
+
+    #[test]
+    fn plain_text_is_kept() {
+        assert_eq!(parsed("hello world"), ("hello world".to_owned(), vec![]));
+        assert_eq!(parsed("snake_case_name"), ("snake_case_name".to_owned(), vec![]));
+        assert_eq!(parsed("**unclosed"), ("**unclosed".to_owned(), vec![]));
+    }
+
+    #[test]
+    fn emphasis_markers() {
+        assert_eq!(
+            parsed("~~s~~ ||sp|| __u__ *i* _i_"),
+            (
+                "s sp u i i".to_owned(),
+                vec![
+                    span(SpanKind::Strikethrough, 0, 1),
+                    span(SpanKind::Spoiler, 2, 2),
+                    span(SpanKind::Underline, 5, 1),
+                    span(SpanKind::Italic, 7, 1),
+                    span(SpanKind::Italic, 9, 1),
+                ]
+            )
+        );
+        assert_eq!(
+            parsed("***both***"),
+            (
+                "both".to_owned(),
+                vec![span(SpanKind::Bold, 0, 4), span(SpanKind::Italic, 0, 4)]
+            )
+        );
+    }
+
+    #[test]
+    fn long_runs_of_markers_are_kept() {
+        assert_eq!(parsed("****x****"), ("****x****".to_owned(), vec![]));
+        assert_eq!(parsed("*****x*****"), ("*****x*****".to_owned(), vec![]));
+        assert_eq!(
+            parsed("**a****b**"),
+            ("a****b".to_owned(), vec![span(SpanKind::Bold, 0, 6)])
+        );
+    }
+
+    #[test]
+    fn nested_emphasis() {
+        assert_eq!(
+            parsed("**bold *both* bold**"),
+            (
+                "bold both bold".to_owned(),
+                vec![span(SpanKind::Bold, 0, 14), span(SpanKind::Italic, 5, 4)]
+            )
+        );
+        // Overlapping markers can't both apply, the first one wins
+        assert_eq!(
+            parsed("**a *b** c*"),
+            ("a *b c*".to_owned(), vec![span(SpanKind::Bold, 0, 4)])
+        );
+    }
+
+    #[test]
+    fn offsets_are_counted_in_utf16() {
+        assert_eq!(
+            parsed("😀 **a**"),
+            ("😀 a".to_owned(), vec![span(SpanKind::Bold, 3, 1)])
+        );
+        assert_eq!(
+            parsed("**😀**"),
+            ("😀".to_owned(), vec![span(SpanKind::Bold, 0, 2)])
+        );
+    }
+
+    #[test]
+    fn escaped_markers_are_literal() {
+        assert_eq!(parsed("\\*no\\*"), ("*no*".to_owned(), vec![]));
+    }
+
+    #[test]
+    fn code_is_not_formatted() {
+        assert_eq!(
+            parsed("`a*b*`"),
+            ("a*b*".to_owned(), vec![span(SpanKind::Code, 0, 4)])
+        );
+        assert_eq!(
+            parsed("```rust\nfn main() {}\n```"),
+            (
+                "fn main() {}".to_owned(),
+                vec![span(SpanKind::Pre(Some("rust".to_owned())), 0, 12)]
+            )
+        );
+    }
+
+    #[test]
+    fn masked_links() {
+        assert_eq!(
+            parsed("[**a**](https://x.y) b"),
+            (
+                "a b".to_owned(),
+                vec![
+                    span(SpanKind::Bold, 0, 1),
+                    span(SpanKind::TextLink("https://x.y".to_owned()), 0, 1),
+                ]
+            )
+        );
+        assert_eq!(
+            parsed("[wiki](https://en.wikipedia.org/wiki/Rust_(language)) tail"),
+            (
+                "wiki tail".to_owned(),
+                vec![span(
+                    SpanKind::TextLink("https://en.wikipedia.org/wiki/Rust_(language)".to_owned()),
+                    0,
+                    4
+                )]
+            )
+        );
+        // Only web links are accepted
+        assert_eq!(parsed("[a](file:///b)"), ("[a](file:///b)".to_owned(), vec![]));
+        assert_eq!(parsed("<https://x.y>"), ("https://x.y".to_owned(), vec![]));
+    }
+
+    #[test]
+    fn quotes_become_italic() {
+        assert_eq!(
+            parsed("> quoted\nnext"),
+            ("┃ quoted\nnext".to_owned(), vec![span(SpanKind::Italic, 2, 6)])
+        );
+        assert_eq!(
+            parsed(">>> a\nb"),
+            (
+                "┃ a\n┃ b".to_owned(),
+                vec![span(SpanKind::Italic, 2, 1), span(SpanKind::Italic, 6, 1)]
+            )
+        );
+    }
+
+    fn rendered(text: &str, entities: &[MessageEntity]) -> String {
+        telegram_to_discord(text, entities)
+    }
+
+    #[test]
+    fn plain_text_is_escaped() {
+        assert_eq!(rendered("plain *text* _x_", &[]), "plain \\*text\\* \\_x\\_");
+        assert_eq!(rendered("> not quote\n# h", &[]), "\\> not quote\n\\# h");
+    }
+
+    #[test]
+    fn entities_become_markers() {
+        assert_eq!(rendered("bold", &[MessageEntity::new_bold(0, 4)]), "**bold**");
+        // Whitespace is moved outside of the markers
+        assert_eq!(rendered(" bold ", &[MessageEntity::new_bold(0, 6)]), " **bold** ");
+        assert_eq!(
+            rendered("a*b", &[MessageEntity::new_italic(0, 3)]),
+            "*a\\*b*"
+        );
+    }
+
+    #[test]
+    fn nested_and_overlapping_entities() {
+        assert_eq!(
+            rendered(
+                "bold italic",
+                &[MessageEntity::new_italic(5, 6), MessageEntity::new_bold(0, 11)]
+            ),
+            "**bold *italic***"
+        );
+        // Partially overlapping entities are dropped
+        assert_eq!(
+            rendered(
+                "abcdef",
+                &[MessageEntity::new_bold(0, 4), MessageEntity::new_italic(2, 4)]
+            ),
+            "**abcd**ef"
+        );
+        // So are the ones out of the text
+        assert_eq!(rendered("out", &[MessageEntity::new_bold(0, 10)]), "out");
+    }
+
+    #[test]
+    fn entity_offsets_are_counted_in_utf16() {
+        assert_eq!(
+            rendered("😀 ab", &[MessageEntity::new_bold(3, 2)]),
+            "😀 **ab**"
+        );
+        assert_eq!(rendered("😀b", &[MessageEntity::new_bold(0, 2)]), "**😀**b");
+        // Entities splitting a surrogate pair are dropped
+        assert_eq!(rendered("😀b", &[MessageEntity::new_bold(1, 2)]), "😀b");
+    }
+
+    #[test]
+    fn code_is_kept_verbatim() {
+        assert_eq!(rendered("a*b", &[MessageEntity::new_code(0, 3)]), "`a*b`");
+        assert_eq!(rendered("a`b", &[MessageEntity::new_code(0, 3)]), "`` a`b ``");
+        assert_eq!(
+            rendered(
+                "fn main() {}",
+                &[MessageEntity::new_pre(0, 12, Some("rust".to_owned()))]
+            ),
+            "```rust\nfn main() {}\n```"
+        );
+    }
+
+    #[test]
+    fn text_links() {
+        assert_eq!(
+            rendered("a*b", &[MessageEntity::new_text_link(0, 3, "https://x.y".to_owned())]),
+            "[a\\*b](https://x.y)"
+        );
+        assert_eq!(
+            rendered(
+                "wiki",
+                &[MessageEntity::new_text_link(0, 4, "https://x.y/Rust_(language)".to_owned())]
+            ),
+            "[wiki](https://x.y/Rust_(language))"
+        );
+        assert_eq!(
+            rendered("smile", &[MessageEntity::new_text_link(0, 5, "https://x.y/:)".to_owned())]),
+            "[smile](https://x.y/:%29)"
+        );
+    }
+
+    #[test]
+    fn links_survive_a_round_trip() {
+        let url = "https://x.y/Rust_(language)";
+        let markdown = rendered("wiki", &[MessageEntity::new_text_link(0, 4, url.to_owned())]);
+        assert_eq!(
+            parsed(&markdown),
+            ("wiki".to_owned(), vec![span(SpanKind::TextLink(url.to_owned()), 0, 4)])
+        );
+    }
+}
@@ -1,28 +1,42 @@
 use crate::prelude::*;
-use rustls::{ServerConfig, ServerConnection};
+use rustls::ServerConfig;
 use slog::Logger;
 use telegram_bot_api::bot::BotApi;
-use telegram_bot_api::methods::SendMessage;
-use tokio::runtime::Runtime;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::os::unix::net::UnixStream;
+use telegram_bot_api::methods::{
+    DeleteMessage, EditMessageCaption, EditMessageText, GetChatMember, SendAudio, SendDocument,
+    SendMediaGroup, SendMessage, SendPhoto, SendVideo,
+};
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use telegram_bot_api::types::{Update, ChatId, MessageEntity};
+use std::time::Duration;
+use telegram_bot_api::types::{
+    ChatId, ChatMember, InputMedia, InputMediaPhoto, InputMediaVideo, Message, MessageEntity,
+    Update,
+};
 
 #[derive(Debug)]
 #[non_exhaustive]
 pub struct AppCommandHandler {
     logger: Logger,
     tgbot: Arc<BotApi>,
-    async_runtime: tokio::runtime::Runtime
+    config: ConfigHandle,
+    store: Arc<dyn MessageStore>,
+    async_runtime: Handle
 }
 
 #[derive(Debug,Default)]
 pub struct AppCommandHandlerBuilder {
     logger: Option<Logger>,
     tgbot: Option<Arc<BotApi>>,
-    async_runtime: Option<tokio::runtime::Runtime>
+    config: Option<ConfigHandle>,
+    store: Option<Arc<dyn MessageStore>>,
+    async_runtime: Option<Handle>
 }
 
 impl AppCommandHandler {
@@ -46,63 +60,425 @@ impl AppCommandHandlerBuilder {
         }
     }
 
-    pub fn runtime(self, runtime: Runtime) -> Self {
+    /// Set the handle of the application's runtime, the command
+    /// server calls the handler from its own threads
+    pub fn runtime(self, runtime: Handle) -> Self {
         Self {
             async_runtime: Some(runtime),
             ..self
         }
     }
 
+    /// Set the handle to the live configuration providing
+    /// the bridge routes
+    pub fn config(self, config: ConfigHandle) -> Self {
+        Self {
+            config: Some(config),
+            ..self
+        }
+    }
+
+    pub fn store(self, store: Arc<dyn MessageStore>) -> Self {
+        Self {
+            store: Some(store),
+            ..self
+        }
+    }
+
     pub fn build(self) -> AppCommandHandler {
         assert!(self.logger.is_some(), "Did not provide a logger for the app command handler");
         assert!(self.tgbot.is_some(), "Did not provide the telegram bot handle for the app command handler");
+        assert!(self.config.is_some(), "Did not provide a config handle for the app command handler");
+        assert!(self.store.is_some(), "Did not provide a message store for the app command handler");
         assert!(self.async_runtime.is_some(), "Did not provide an async runtime for the app command handler");
 
         AppCommandHandler {
             logger: self.logger.unwrap(),
             tgbot: self.tgbot.unwrap(),
+            config: self.config.unwrap(),
+            store: self.store.unwrap(),
             async_runtime: self.async_runtime.unwrap()
         }
     }
 }
 
+/// Build the text of a relayed message along with the
+/// entity highlighting the author's name and the entities
+/// converted from the discord markdown of the content
+fn relayed_text(author: &str, content: &str) -> (String, Vec<MessageEntity>) {
+    let name_len = author.encode_utf16().count() as i64;
+    let header = format!("{} пишет:\n", author);
+    let header_len = header.encode_utf16().count() as i64;
+    let content = discord_to_telegram(content);
+
+    let text = format!("{}{}", header, content.text);
+    let mut entities = vec![MessageEntity::new_bold(0, name_len)];
+    entities.extend(content.entities(header_len));
+    (text, entities)
+}
+
+/// Maximal length of a media caption in UTF-16 code units
+const CAPTION_MAX_LEN: usize = 1024;
+/// Maximal number of items in a single media group
+const MEDIA_GROUP_MAX_LEN: usize = 10;
+
+/// Post the attachments to the chat, choosing the telegram method
+/// from their type and count. Visual media are grouped into albums,
+/// the caption goes to the first posted item. The posted messages
+/// are pushed to `sent`, even if a later upload fails
+async fn upload_attachments(
+    tgbot: Arc<BotApi>,
+    chat_id: i64,
+    caption: Option<(String, Vec<MessageEntity>)>,
+    reply_to_message_id: Option<i64>,
+    attachments: Vec<Attachment>,
+    sent: &mut Vec<Message>,
+) -> UResult {
+    let chat = ChatId::IntType(chat_id);
+    let mut caption = caption;
+
+    let groupable = attachments.len() > 1
+        && attachments.iter().all(|a| UploadKind::of(a).is_visual());
+    if groupable {
+        for chunk in attachments.chunks(MEDIA_GROUP_MAX_LEN) {
+            let mut media = Vec::new();
+            for attachment in chunk {
+                let (text, entities) = caption.take().unzip();
+                let item = match UploadKind::of(attachment) {
+                    UploadKind::Photo => {
+                        let mut item = InputMediaPhoto::new(input_file(attachment)?);
+                        item.caption = text;
+                        item.caption_entities = entities;
+                        InputMedia::Photo(item)
+                    }
+                    _ => {
+                        let mut item = InputMediaVideo::new(input_file(attachment)?);
+                        item.caption = text;
+                        item.caption_entities = entities;
+                        InputMedia::Video(item)
+                    }
+                };
+                media.push(item);
+            }
+            let mut request = SendMediaGroup::new(chat.clone(), media);
+            request.reply_to_message_id = reply_to_message_id;
+            request.allow_sending_without_reply = Some(true);
+            match tgbot.send_media_group(request).await {
+                Ok(messages) => sent.extend(messages),
+                Err(why) => return Err(format!("sendMediaGroup failed: {:#?}", why).into()),
+            }
+        }
+        return Ok(());
+    }
+
+    for attachment in attachments.iter() {
+        let (text, entities) = caption.take().unzip();
+        let file = input_file(attachment)?;
+        let result = match UploadKind::of(attachment) {
+            UploadKind::Photo => {
+                let mut request = SendPhoto::new(chat.clone(), file);
+                request.caption = text;
+                request.caption_entities = entities;
+                request.reply_to_message_id = reply_to_message_id;
+                request.allow_sending_without_reply = Some(true);
+                tgbot.send_photo(request).await
+            }
+            UploadKind::Video => {
+                let mut request = SendVideo::new(chat.clone(), file);
+                request.caption = text;
+                request.caption_entities = entities;
+                request.reply_to_message_id = reply_to_message_id;
+                request.allow_sending_without_reply = Some(true);
+                tgbot.send_video(request).await
+            }
+            UploadKind::Audio => {
+                let mut request = SendAudio::new(chat.clone(), file);
+                request.caption = text;
+                request.caption_entities = entities;
+                request.reply_to_message_id = reply_to_message_id;
+                request.allow_sending_without_reply = Some(true);
+                tgbot.send_audio(request).await
+            }
+            UploadKind::Document => {
+                let mut request = SendDocument::new(chat.clone(), file);
+                request.caption = text;
+                request.caption_entities = entities;
+                request.reply_to_message_id = reply_to_message_id;
+                request.allow_sending_without_reply = Some(true);
+                tgbot.send_document(request).await
+            }
+        };
+        match result {
+            Ok(message) => sent.push(message),
+            Err(why) => {
+                return Err(format!("Could not upload {}: {:#?}", attachment.file_name, why).into())
+            }
+        }
+    }
+    Ok(())
+}
+
+impl AppCommandHandler {
+    /// Find the telegram message of the given chat corresponding
+    /// to the message a discord user replied to
+    fn resolve_reply(&self, from: &ActorInfos, chat_id: i64, reply: &MessageRef) -> UResult<Option<i64>> {
+        let message_id = match reply.bot_family {
+            BotFamily::Discord => self
+                .store
+                .find_by_discord(&from.server, &from.channel, &reply.message_id)?
+                .into_iter()
+                .find(|l| l.origin == LinkOrigin::Discord && l.telegram.chat_id == chat_id)
+                .map(|l| l.telegram.message_id),
+            // Telegram originals only exist in their own chat, and
+            // must have been relayed to the channel of the reply
+            BotFamily::Telegram => match TelegramMessageRef::from_reference(&reply.message_id) {
+                Some(original) if original.chat_id == chat_id => self
+                    .store
+                    .find_by_telegram(&original)?
+                    .into_iter()
+                    .find(|l| {
+                        l.origin == LinkOrigin::Telegram
+                            && l.discord.server == from.server
+                            && l.discord.channel == from.channel
+                    })
+                    .map(|l| l.telegram.message_id),
+                _ => None,
+            },
+            _ => None,
+        };
+        Ok(message_id)
+    }
+}
+
 impl CommandHandler for AppCommandHandler {
     fn forward_message(&self, msg: Command) -> UResult {
-        if let CommandKind::ForwardMessage { from, to: _, content } = msg.kind {
-            let name_len = from.name.encode_utf16().count() as i64;
-            let content = format!(
-                "{} пишет:\n{}", from.name, content
-            );
-            let m = {
-                let mut m = SendMessage::new(ChatId::IntType(-1001898024643 as i64), content);
-                let entities = vec![
-                    MessageEntity::new_bold(0, name_len),
-                ];
-                m.entities = Some(entities);
-                m
-            };
-            let tgbot = self.tgbot.clone();
-            let logger = self.logger.clone();
-            self.async_runtime.block_on(self.async_runtime.spawn(async move {
-                // if let Ok(_) = tgbot.send_message(m.clone()).await {
-                    // return;
-                // }
-                // let m = SendMessage { parse_mode: None, ..m };
-                if let Err(why) = tgbot.send_message(m).await {
-                    error!(logger, "Could not send a message; reason: {:#?}", why);
+        if let CommandKind::ForwardMessage { from, to: _, message_id, reply_to, content, attachments } = msg.kind {
+            let settings = self.config.snapshot();
+            let targets = settings.routes.telegram_targets(&from.server, &from.channel);
+            if targets.is_empty() {
+                warn!(self.logger, "No route for the incoming message, dropping it";
+                    "server" => &from.server,
+                    "channel" => &from.channel
+                );
+                return Ok(());
+            }
+
+            // Attachments telegram would refuse are replaced by links
+            let (attachments, oversized): (Vec<_>, Vec<_>) = attachments
+                .into_iter()
+                .partition(|a| !exceeds_upload_limit(a));
+            let mut content = content;
+            for attachment in oversized.iter() {
+                warn!(self.logger, "Attachment exceeds telegram size limits, sending a link instead";
+                    "file" => &attachment.file_name,
+                    "size" => attachment.size
+                );
+                content = format!("{}\n{}", content, attachment_link(attachment));
+            }
+
+            for chat_id in targets {
+                let reply_to_message_id = match reply_to {
+                    Some(ref reply) => self.resolve_reply(&from, *chat_id, &reply.message)?,
+                    None => None,
+                };
+                // Without a known counterpart the context of the reply is
+                // kept by quoting the beginning of the original message
+                let (content, entities) = match (reply_to.as_ref(), reply_to_message_id) {
+                    (Some(reply), None) => relayed_text(
+                        &from.name,
+                        &format!("{}{}", quote_snippet(&reply.author, &reply.content), content),
+                    ),
+                    _ => relayed_text(&from.name, &content),
+                };
+
+                // Text too long for a caption is posted on its own
+                // before the attachments
+                let caption_fits = content.encode_utf16().count() <= CAPTION_MAX_LEN;
+                let mut sent = Vec::new();
+                if attachments.is_empty() || !caption_fits {
+                    let m = {
+                        let mut m = SendMessage::new(ChatId::IntType(*chat_id), content.clone());
+                        m.entities = Some(entities.clone());
+                        m.reply_to_message_id = reply_to_message_id;
+                        m.allow_sending_without_reply = Some(true);
+                        m
+                    };
+                    let result = self.async_runtime.block_on(self.tgbot.send_message(m));
+                    match result {
+                        Ok(message) => sent.push(message),
+                        Err(why) => {
+                            error!(self.logger, "Could not send a message; reason: {:#?}", why);
+                            continue;
+                        }
+                    }
+                }
+                if !attachments.is_empty() {
+                    let caption = if caption_fits { Some((content, entities)) } else { None };
+                    let upload = upload_attachments(
+                        self.tgbot.clone(),
+                        *chat_id,
+                        caption,
+                        reply_to_message_id,
+                        attachments.clone(),
+                        &mut sent,
+                    );
+                    // What was posted before a failure is still recorded
+                    if let Err(why) = self.async_runtime.block_on(upload) {
+                        error!(self.logger, "Could not upload attachments; reason: {:#?}", why);
+                    }
                 }
-            }));
+
+                for message in sent {
+                    self.store.record(MessageLink {
+                        origin: LinkOrigin::Discord,
+                        telegram: TelegramMessageRef {
+                            chat_id: *chat_id,
+                            message_id: message.message_id,
+                        },
+                        discord: DiscordMessageRef {
+                            server: from.server.clone(),
+                            channel: from.channel.clone(),
+                            message_id: Some(message_id.clone()),
+                        },
+                        text: TextPlacement::of(&message),
+                        relayed_at: MessageLink::now(),
+                    })?;
+                }
+            }
             Ok(())
         } else {
             Err("Wrong command kind received, expected ForwardMessage".into())
         }
     }
+
+    fn edit_message(&self, msg: Command) -> UResult {
+        if let CommandKind::EditMessage { from, to: _, message_id, content } = msg.kind {
+            let links = self.store.find_by_discord(&from.server, &from.channel, &message_id)?;
+            if links.is_empty() {
+                debug!(self.logger, "Edited message was never relayed, skipping";
+                    "server" => &from.server,
+                    "channel" => &from.channel,
+                    "message_id" => &message_id
+                );
+                return Ok(());
+            }
+
+            let (content, entities) = relayed_text(&from.name, &content);
+            for link in links.into_iter().filter(|l| l.origin == LinkOrigin::Discord) {
+                // The edit methods return different types, errors are
+                // formatted on the spot
+                let result = match link.text {
+                    TextPlacement::Text => {
+                        let mut m = EditMessageText::new(content.clone());
+                        m.chat_id = Some(ChatId::IntType(link.telegram.chat_id));
+                        m.message_id = Some(link.telegram.message_id);
+                        m.entities = Some(entities.clone());
+                        self.async_runtime
+                            .block_on(self.tgbot.edit_message_text(m))
+                            .map(|_| ())
+                            .map_err(|why| format!("{:#?}", why))
+                    }
+                    TextPlacement::Caption if content.encode_utf16().count() > CAPTION_MAX_LEN => {
+                        warn!(self.logger, "Edited text is too long for a caption, skipping";
+                            "chat" => link.telegram.chat_id,
+                            "message_id" => link.telegram.message_id
+                        );
+                        continue;
+                    }
+                    TextPlacement::Caption => {
+                        let mut m = EditMessageCaption::new();
+                        m.chat_id = Some(ChatId::IntType(link.telegram.chat_id));
+                        m.message_id = Some(link.telegram.message_id);
+                        m.caption = Some(content.clone());
+                        m.caption_entities = Some(entities.clone());
+                        self.async_runtime
+                            .block_on(self.tgbot.edit_message_caption(m))
+                            .map(|_| ())
+                            .map_err(|why| format!("{:#?}", why))
+                    }
+                    // The other items of an album carry no text
+                    TextPlacement::Absent => continue,
+                };
+                if let Err(why) = result {
+                    error!(self.logger, "Could not edit a message; reason: {}", why);
+                }
+            }
+            Ok(())
+        } else {
+            Err("Wrong command kind received, expected EditMessage".into())
+        }
+    }
+
+    fn delete_message(&self, msg: Command) -> UResult {
+        if let CommandKind::DeleteMessage { from, to: _, message } = msg.kind {
+            let copies: Vec<TelegramMessageRef> = match message.bot_family {
+                // A discord message was deleted, remove its relayed copies
+                BotFamily::Discord => self
+                    .store
+                    .find_by_discord(&from.server, &from.channel, &message.message_id)?
+                    .into_iter()
+                    .filter(|l| l.origin == LinkOrigin::Discord)
+                    .map(|l| l.telegram)
+                    .collect(),
+                // The discord copy of a telegram message was deleted,
+                // remove the telegram original as well
+                BotFamily::Telegram => {
+                    let original = match TelegramMessageRef::from_reference(&message.message_id) {
+                        Some(original) => original,
+                        None => {
+                            warn!(self.logger, "Deleted message has no telegram chat, skipping";
+                                "message_id" => &message.message_id
+                            );
+                            return Ok(());
+                        }
+                    };
+                    let settings = self.config.snapshot();
+                    let routed = settings
+                        .routes
+                        .telegram_targets(&from.server, &from.channel)
+                        .contains(&original.chat_id);
+                    let relayed = self.store.find_by_telegram(&original)?.into_iter().any(|l| {
+                        l.origin == LinkOrigin::Telegram
+                            && l.discord.server == from.server
+                            && l.discord.channel == from.channel
+                    });
+                    if routed && relayed {
+                        vec![original]
+                    } else {
+                        Vec::new()
+                    }
+                }
+                _ => Vec::new(),
+            };
+            if copies.is_empty() {
+                debug!(self.logger, "Deleted message was never relayed, skipping";
+                    "server" => &from.server,
+                    "channel" => &from.channel,
+                    "message_id" => &message.message_id
+                );
+                return Ok(());
+            }
+
+            for copy in copies {
+                let m = DeleteMessage::new(ChatId::IntType(copy.chat_id), copy.message_id);
+                if let Err(why) = self.async_runtime.block_on(self.tgbot.delete_message(m)) {
+                    error!(self.logger, "Could not delete a message; reason: {:#?}", why);
+                }
+            }
+            Ok(())
+        } else {
+            Err("Wrong command kind received, expected DeleteMessage".into())
+        }
+    }
 }
 
 /// Default implementation of an update handler
 #[derive(Debug)]
 pub struct DefaultUpdateHandler {
-    discord_sender: Option<Arc<CommandSender>>,
+    media_downloader: Option<Arc<MediaDownloader>>,
+    tgbot: Arc<BotApi>,
+    config: ConfigHandle,
+    store: Arc<dyn MessageStore>,
     logger: Logger,
 }
 impl DefaultUpdateHandler {
@@ -114,14 +490,40 @@ impl DefaultUpdateHandler {
 
 #[derive(Default, Debug)]
 pub struct DefaultUpdateHandlerBuilder {
-    discord_sender: Option<Arc<CommandSender>>,
+    media_downloader: Option<Arc<MediaDownloader>>,
+    tgbot: Option<Arc<BotApi>>,
+    config: Option<ConfigHandle>,
+    store: Option<Arc<dyn MessageStore>>,
     logger: Option<Logger>,
 }
 
 impl DefaultUpdateHandlerBuilder {
-    pub fn discord_sender(self, sender: Arc<CommandSender>) -> Self {
+    pub fn media_downloader(self, downloader: Arc<MediaDownloader>) -> Self {
         Self {
-            discord_sender: Some(sender),
+            media_downloader: Some(downloader),
+            ..self
+        }
+    }
+
+    pub fn bot(self, tgbot: Arc<BotApi>) -> Self {
+        Self {
+            tgbot: Some(tgbot),
+            ..self
+        }
+    }
+
+    /// Set the handle to the live configuration providing the
+    /// bridge routes and the integrations
+    pub fn config(self, config: ConfigHandle) -> Self {
+        Self {
+            config: Some(config),
+            ..self
+        }
+    }
+
+    pub fn store(self, store: Arc<dyn MessageStore>) -> Self {
+        Self {
+            store: Some(store),
             ..self
         }
     }
@@ -138,34 +540,384 @@ impl DefaultUpdateHandlerBuilder {
             self.logger.is_some(),
             "Did not provide a logger for the default update handler"
         );
+        assert!(
+            self.config.is_some(),
+            "Did not provide a config handle for the default update handler"
+        );
+        assert!(
+            self.store.is_some(),
+            "Did not provide a message store for the default update handler"
+        );
+        assert!(
+            self.tgbot.is_some(),
+            "Did not provide the telegram bot handle for the default update handler"
+        );
 
         DefaultUpdateHandler {
-            discord_sender: self.discord_sender,
+            media_downloader: self.media_downloader,
+            tgbot: self.tgbot.unwrap(),
+            config: self.config.unwrap(),
+            store: self.store.unwrap(),
             logger: self.logger.unwrap(),
         }
     }
 }
 
+fn author_name(msg: &Message) -> String {
+    if msg.from.is_none() {
+        "Unknown".to_owned()
+    } else {
+        format_user_name(&msg.from.as_ref().unwrap())
+    }
+}
+
+/// Check whether the message is the bridge's delete command,
+/// optionally addressed to a bot by its username
+fn is_delete_command(msg: &Message) -> bool {
+    match msg.text.as_deref() {
+        Some(text) => {
+            let command = text.split_whitespace().next().unwrap_or_default();
+            command == "/delete" || command.starts_with("/delete@")
+        }
+        None => false,
+    }
+}
+
+/// Text of a message, or its caption for media messages
+fn message_content(msg: &Message) -> String {
+    msg.text
+        .clone()
+        .or_else(|| msg.caption.clone())
+        .unwrap_or_default()
+}
+
+/// Quote of the beginning of a replied message, the markdown
+/// characters of its plain text and author are escaped
+fn discord_quote(parent: &Message) -> String {
+    format!(
+        "> {}: {}\n",
+        escape_discord(&author_name(parent), false),
+        escape_discord(&snippet(&message_content(parent)), false)
+    )
+}
+
+/// Content of a message converted to discord markdown along
+/// with its formatting
+fn markdown_content(msg: &Message) -> String {
+    match (msg.text.as_ref(), msg.caption.as_ref()) {
+        (Some(text), _) => telegram_to_discord(text, msg.entities.as_deref().unwrap_or_default()),
+        (None, Some(caption)) => {
+            telegram_to_discord(caption, msg.caption_entities.as_deref().unwrap_or_default())
+        }
+        (None, None) => String::new(),
+    }
+}
+
+impl DefaultUpdateHandler {
+    /// Find the message of the given discord channel corresponding
+    /// to the message a telegram user replied to
+    fn resolve_reply(
+        &self,
+        chat_id: i64,
+        parent: &Message,
+        target: &DiscordChannel,
+    ) -> UResult<Option<ReplyRef>> {
+        let original = TelegramMessageRef {
+            chat_id,
+            message_id: parent.message_id,
+        };
+        let link = self
+            .store
+            .find_by_telegram(&original)?
+            .into_iter()
+            .find(|l| l.discord.server == target.server && l.discord.channel == target.channel);
+        let message = match link {
+            Some(MessageLink {
+                origin: LinkOrigin::Telegram,
+                ..
+            }) => MessageRef {
+                bot_family: BotFamily::Telegram,
+                message_id: original.reference(),
+            },
+            Some(MessageLink {
+                origin: LinkOrigin::Discord,
+                discord:
+                    DiscordMessageRef {
+                        message_id: Some(message_id),
+                        ..
+                    },
+                ..
+            }) => MessageRef {
+                bot_family: BotFamily::Discord,
+                message_id,
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(ReplyRef {
+            message,
+            author: author_name(parent),
+            content: message_content(parent),
+        }))
+    }
+
+    /// Download the file attached to the message, if any, so that
+    /// the discord bot could upload it
+    async fn fetch_attachments(&self, msg: &Message) -> Vec<Attachment> {
+        let media = match TelegramMedia::from_message(msg) {
+            Some(media) => media,
+            None => return Vec::new(),
+        };
+        let downloader = match self.media_downloader {
+            Some(ref downloader) => downloader,
+            None => {
+                warn!(self.logger, "No media downloader configured, dropping the attachment";
+                    "file" => &media.file_name
+                );
+                return Vec::new();
+            }
+        };
+        match downloader.download(&media).await {
+            Ok(attachment) => vec![attachment],
+            Err(why) => {
+                error!(self.logger, "Could not download an attachment; reason: {:#?}", why;
+                    "file" => &media.file_name
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    async fn is_chat_admin(&self, chat_id: i64, user_id: i64) -> UResult<bool> {
+        let request = GetChatMember::new(ChatId::IntType(chat_id), user_id);
+        match self.tgbot.get_chat_member(request).await {
+            Ok(ChatMember::Owner(_)) | Ok(ChatMember::Administrator(_)) => Ok(true),
+            Ok(_) => Ok(false),
+            Err(why) => Err(format!("Could not fetch the chat member: {:#?}", why).into()),
+        }
+    }
+
+    /// Check whether the message was sent by an administrator of
+    /// its chat, anonymous administrators post on behalf of the
+    /// chat itself
+    async fn sent_by_admin(&self, msg: &Message) -> UResult<bool> {
+        if msg.sender_chat.as_ref().map(|chat| chat.id) == Some(msg.chat.id) {
+            return Ok(true);
+        }
+        match msg.from.as_ref() {
+            Some(user) => self.is_chat_admin(msg.chat.id, user.id).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_on_telegram(&self, chat_id: i64, message_id: i64) {
+        let request = DeleteMessage::new(ChatId::IntType(chat_id), message_id);
+        if let Err(why) = self.tgbot.delete_message(request).await {
+            error!(self.logger, "Could not delete a message; reason: {:#?}", why);
+        }
+    }
+
+    /// Send the command to the discord bot, if any, without
+    /// blocking the runtime on the synchronous socket write
+    async fn send_to_discord(&self, cmd: Command) -> UResult {
+        let sender = match self.config.snapshot().discord_sender {
+            Some(ref sender) => sender.clone(),
+            None => return Ok(()),
+        };
+        tokio::task::spawn_blocking(move || sender.send(cmd)).await??;
+        Ok(())
+    }
+
+    /// Handle the `/delete` command sent by a chat administrator
+    /// in reply to a message, which deletes the message along with
+    /// all its relayed copies
+    ///
+    /// The Bot API does not notify bots about deleted messages, so
+    /// this service flow is the only way for telegram moderators to
+    /// propagate a deletion across the bridge
+    async fn delete_command(&self, msg: Message) -> UResult {
+        let chat_id = msg.chat.id;
+        if !self.sent_by_admin(&msg).await? {
+            warn!(self.logger, "Delete command issued by a non-administrator, ignoring";
+                "chat" => chat_id,
+                "user" => msg.from.as_ref().map(|user| user.id)
+            );
+            return Ok(());
+        }
+        let target = match msg.reply_to_message {
+            Some(target) => target,
+            None => {
+                debug!(self.logger, "Delete command does not reply to any message, ignoring");
+                return Ok(());
+            }
+        };
+
+        let original = TelegramMessageRef {
+            chat_id,
+            message_id: target.message_id,
+        };
+        for link in self.store.find_by_telegram(&original)? {
+            let message = match link.origin {
+                LinkOrigin::Telegram => MessageRef {
+                    bot_family: BotFamily::Telegram,
+                    message_id: original.reference(),
+                },
+                LinkOrigin::Discord => match link.discord.message_id {
+                    Some(message_id) => MessageRef {
+                        bot_family: BotFamily::Discord,
+                        message_id,
+                    },
+                    None => continue,
+                },
+            };
+            let cmd = Command {
+                kind: CommandKind::DeleteMessage {
+                    from: ActorInfos {
+                        server: format!("{}", chat_id),
+                        channel: format!("{}", chat_id),
+                        name: author_name(&msg),
+                    },
+                    to: ActorInfos {
+                        server: link.discord.server,
+                        channel: link.discord.channel,
+                        name: Default::default(),
+                    },
+                    message,
+                },
+                sender_bot_family: BotFamily::Telegram,
+                protocol_version: qcproto::types::PROTOCOL_VERSION
+            };
+            self.send_to_discord(cmd).await?;
+        }
+
+        self.delete_on_telegram(chat_id, target.message_id).await;
+        self.delete_on_telegram(chat_id, msg.message_id).await;
+        info!(self.logger, "Deleted a message on administrator's request";
+            "chat" => chat_id,
+            "message_id" => target.message_id
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
 impl UpdateHandler for DefaultUpdateHandler {
-    fn message(&self, msg: telegram_bot_api::types::Message) -> UResult {
+    async fn message(&self, msg: Message) -> UResult {
         info!(self.logger, "Received a message object!");
-        let author = if msg.from.is_none() {
-            "Unknown".to_owned()
-        } else {
-            format_user_name(&msg.from.as_ref().unwrap())
+        let settings = self.config.snapshot();
+        let targets = settings.routes.discord_targets(msg.chat.id);
+        if targets.is_empty() {
+            warn!(self.logger, "No route for the telegram chat, dropping the message";
+                "chat" => msg.chat.id
+            );
+            return Ok(());
+        }
+        if is_delete_command(&msg) {
+            return self.delete_command(msg).await;
+        }
+
+        let author = author_name(&msg);
+        let content = markdown_content(&msg);
+        let attachments = self.fetch_attachments(&msg).await;
+        if content.is_empty() && attachments.is_empty() {
+            debug!(self.logger, "Nothing to forward in the message, skipping";
+                "chat" => msg.chat.id,
+                "message_id" => msg.message_id
+            );
+            return Ok(());
+        }
+
+        let original = TelegramMessageRef {
+            chat_id: msg.chat.id,
+            message_id: msg.message_id,
         };
-        let cmd = Command {
-            kind: CommandKind::ForwardMessage {
-                from: ActorInfos { server: format!("{}", msg.chat.id), name: author },
-                to: ActorInfos { server: "1032941443058241546".to_string(), name: Default::default() },
-                content: msg.text.unwrap_or(Default::default())
-            },
-            sender_bot_family: BotFamily::Telegram,
-            protocol_version: qcproto::types::PROTOCOL_VERSION
+        for target in targets {
+            let reply_to = match msg.reply_to_message {
+                Some(ref parent) => self.resolve_reply(msg.chat.id, parent, target)?,
+                None => None,
+            };
+            // Without a known counterpart the context of the reply is
+            // kept by quoting the beginning of the original message
+            let content = match (msg.reply_to_message.as_ref(), reply_to.as_ref()) {
+                (Some(parent), None) => format!("{}{}", discord_quote(parent), content),
+                _ => content.clone(),
+            };
+            let cmd = Command {
+                kind: CommandKind::ForwardMessage {
+                    from: ActorInfos {
+                        server: format!("{}", msg.chat.id),
+                        channel: format!("{}", msg.chat.id),
+                        name: author.clone(),
+                    },
+                    to: ActorInfos {
+                        server: target.server.clone(),
+                        channel: target.channel.clone(),
+                        name: Default::default(),
+                    },
+                    message_id: original.reference(),
+                    reply_to,
+                    content,
+                    attachments: attachments.clone(),
+                },
+                sender_bot_family: BotFamily::Telegram,
+                protocol_version: qcproto::types::PROTOCOL_VERSION
+            };
+
+            if settings.discord_sender.is_some() {
+                self.send_to_discord(cmd).await?;
+                self.store.record(MessageLink {
+                    origin: LinkOrigin::Telegram,
+                    telegram: original.clone(),
+                    discord: DiscordMessageRef {
+                        server: target.server.clone(),
+                        channel: target.channel.clone(),
+                        message_id: None,
+                    },
+                    text: TextPlacement::of(&msg),
+                    relayed_at: MessageLink::now(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn edited_message(&self, msg: Message) -> UResult {
+        info!(self.logger, "Received an edited message object!");
+        let original = TelegramMessageRef {
+            chat_id: msg.chat.id,
+            message_id: msg.message_id,
         };
+        let links = self.store.find_by_telegram(&original)?;
+        if links.is_empty() {
+            debug!(self.logger, "Edited message was never relayed, skipping";
+                "chat" => msg.chat.id,
+                "message_id" => msg.message_id
+            );
+            return Ok(());
+        }
+
+        let author = author_name(&msg);
+        let content = markdown_content(&msg);
+        for link in links.into_iter().filter(|l| l.origin == LinkOrigin::Telegram) {
+            let cmd = Command {
+                kind: CommandKind::EditMessage {
+                    from: ActorInfos {
+                        server: format!("{}", msg.chat.id),
+                        channel: format!("{}", msg.chat.id),
+                        name: author.clone(),
+                    },
+                    to: ActorInfos {
+                        server: link.discord.server,
+                        channel: link.discord.channel,
+                        name: Default::default(),
+                    },
+                    message_id: original.reference(),
+                    content: content.clone(),
+                },
+                sender_bot_family: BotFamily::Telegram,
+                protocol_version: qcproto::types::PROTOCOL_VERSION
+            };
 
-        if let Some(ref sender) = self.discord_sender {
-            sender.send(cmd)?;
+            self.send_to_discord(cmd).await?;
         }
         Ok(())
     }
@@ -173,8 +925,16 @@ impl UpdateHandler for DefaultUpdateHandler {
 
 /// Default implementation of an update handler
 pub struct DefaultStreamHandler {
-    dispatcher: Arc<dyn Dispatcher<Update>>,
-    tls_config: ServerConfig,
+    dispatcher: Arc<dyn UpdateDispatcher>,
+    tls_acceptor: TlsAcceptor,
+    webhook_path: String,
+    secret_token: Option<String>,
+    idle_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    retry_after: Duration,
+    rejected_requests: AtomicU64,
+    stop_signal: watch::Sender<bool>,
     logger: Logger,
 }
 
@@ -182,14 +942,20 @@ pub struct DefaultStreamHandler {
 /// a default update handler
 #[derive(Default)]
 pub struct DefaultStreamHandlerBuilder {
-    dispatcher: Option<Arc<dyn Dispatcher<Update>>>,
+    dispatcher: Option<Arc<dyn UpdateDispatcher>>,
     tls_config: Option<ServerConfig>,
+    webhook_path: Option<String>,
+    secret_token: Option<String>,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retry_after: Option<Duration>,
     logger: Option<Logger>,
 }
 
 impl DefaultStreamHandlerBuilder {
     /// Set the data dispatcher
-    pub fn dispatcher(self, dispatcher: Arc<dyn Dispatcher<Update>>) -> Self {
+    pub fn dispatcher(self, dispatcher: Arc<dyn UpdateDispatcher>) -> Self {
         Self {
             dispatcher: Some(dispatcher),
             ..self
@@ -204,6 +970,60 @@ impl DefaultStreamHandlerBuilder {
         }
     }
 
+    /// Set the path on which telegram posts the updates,
+    /// requests on any other path are refused
+    pub fn webhook_path(self, path: &str) -> Self {
+        Self {
+            webhook_path: Some(path.to_owned()),
+            ..self
+        }
+    }
+
+    /// Set the secret token expected in the
+    /// `X-Telegram-Bot-Api-Secret-Token` header of every request,
+    /// requests are not authenticated if none is provided
+    pub fn secret_token(self, secret_token: Option<String>) -> Self {
+        Self {
+            secret_token,
+            ..self
+        }
+    }
+
+    /// Set the time a kept alive connection may stay idle
+    /// between two requests
+    pub fn idle_timeout(self, timeout: Duration) -> Self {
+        Self {
+            idle_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Set the time allowed to complete the TLS handshake and
+    /// to receive a whole request
+    pub fn read_timeout(self, timeout: Duration) -> Self {
+        Self {
+            read_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Set the time allowed to write a response
+    pub fn write_timeout(self, timeout: Duration) -> Self {
+        Self {
+            write_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Set the delay telegram is asked to wait before retrying
+    /// a request refused for lack of capacity
+    pub fn retry_after(self, delay: Duration) -> Self {
+        Self {
+            retry_after: Some(delay),
+            ..self
+        }
+    }
+
     /// Set the integrated logger
     pub fn logger(self, logger: Logger) -> Self {
         Self {
@@ -230,33 +1050,222 @@ impl DefaultStreamHandlerBuilder {
 
         DefaultStreamHandler {
             dispatcher: self.dispatcher.unwrap(),
-            tls_config: self.tls_config.unwrap(),
+            tls_acceptor: TlsAcceptor::from(Arc::new(self.tls_config.unwrap())),
+            webhook_path: self.webhook_path.unwrap_or("/".to_owned()),
+            secret_token: self.secret_token,
+            idle_timeout: self.idle_timeout.unwrap_or(Duration::from_secs(30)),
+            read_timeout: self.read_timeout.unwrap_or(Duration::from_secs(10)),
+            write_timeout: self.write_timeout.unwrap_or(Duration::from_secs(10)),
+            retry_after: self.retry_after.unwrap_or(Duration::from_secs(5)),
+            rejected_requests: AtomicU64::new(0),
+            stop_signal: watch::channel(false).0,
             logger: self.logger.unwrap(),
         }
     }
 }
 
+/// Header carrying the secret token registered with the webhook
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Maximal size of an update body, telegram updates are
+/// only a few kilobytes
+const MAX_UPDATE_SIZE: usize = 1024 * 1024;
+
+/// Time allowed to a refused client to complete the TLS
+/// handshake before being answered
+const REJECT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
 impl DefaultStreamHandler {
     /// Instantiate a new default stream handler
     pub fn new() -> DefaultStreamHandlerBuilder {
         Default::default()
     }
+
+    /// Number of requests rejected because of a missing or
+    /// invalid secret token
+    pub fn rejected_requests(&self) -> u64 {
+        self.rejected_requests.load(Ordering::Relaxed)
+    }
+
+    /// Complete the TLS handshake within the read timeout,
+    /// returns `None` if the client was too slow
+    async fn accept_tls(
+        &self,
+        stream: TcpStream,
+        peer: &str,
+        timeout: Duration,
+    ) -> UResult<Option<TlsStream<TcpStream>>> {
+        let handshake = self.tls_acceptor.accept(stream);
+        match tokio::time::timeout(timeout, handshake).await {
+            Ok(stream) => Ok(Some(stream?)),
+            Err(_) => {
+                debug!(self.logger, "TLS handshake timed out"; "peer" => peer.to_owned());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Validate the request and extract the update it carries,
+    /// the error holds the response refusing the request
+    fn route(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        peer: &str,
+    ) -> Result<Update, http::Response<String>> {
+        if request.uri().path() != self.webhook_path {
+            debug!(self.logger, "Webhook request on an unknown path";
+                "path" => request.uri().path().to_owned(),
+                "peer" => peer.to_owned()
+            );
+            return Err(status_response(http::StatusCode::NOT_FOUND, "not found"));
+        }
+        if request.method() != http::Method::POST {
+            let mut response =
+                status_response(http::StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+            response
+                .headers_mut()
+                .insert(http::header::ALLOW, http::HeaderValue::from_static("POST"));
+            return Err(response);
+        }
+        if !self.is_authorized(request) {
+            let rejected = self.rejected_requests.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(self.logger, "Rejected a webhook request with an invalid secret token";
+                "peer" => peer.to_owned(),
+                "rejected_total" => rejected
+            );
+            return Err(status_response(http::StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        serde_json::from_slice::<Update>(request.body()).map_err(|why| {
+            warn!(self.logger, "Received a malformed update: {}", why;
+                "peer" => peer.to_owned()
+            );
+            status_response(http::StatusCode::BAD_REQUEST, "malformed update")
+        })
+    }
+
+    /// Check the secret token of the request, in constant time to
+    /// not let an attacker guess it byte by byte
+    fn is_authorized<T>(&self, request: &http::Request<T>) -> bool {
+        let expected = match self.secret_token {
+            Some(ref token) => token,
+            None => return true,
+        };
+        match request.headers().get(SECRET_TOKEN_HEADER) {
+            Some(provided) => constant_time_eq(provided.as_bytes(), expected.as_bytes()),
+            None => false,
+        }
+    }
 }
 
-impl StreamHandler<TcpStream> for DefaultStreamHandler {
-    fn handle_stream(&self, mut stream: TcpStream) -> UResult {
-        let response = http::Response::builder()
-            .version(http::Version::HTTP_11)
-            .status(200)
-            .header("Content-Type", "application/json")
-            .body(r#"{"result":"ok"}"#)
-            .unwrap();
-        let mut conn = ServerConnection::new(Arc::new(self.tls_config.clone()))?;
-        let mut stream = rustls::Stream::new(&mut conn, &mut stream);
-        let request = read_http_request(&mut stream)?;
-        let update = serde_json::from_str::<Update>(request.body())?;
-        write_http_response(&mut stream, response)?;
-        self.dispatcher.dispatch(update)?;
+#[async_trait]
+impl AsyncStreamHandler<TcpStream> for DefaultStreamHandler {
+    async fn handle_stream(&self, stream: TcpStream) -> UResult {
+        let peer = stream.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+        let stream = match self.accept_tls(stream, &peer, self.read_timeout).await? {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let mut connection = HttpConnection::new(stream, MAX_UPDATE_SIZE)
+            .with_idle_timeout(self.idle_timeout)
+            .with_request_timeout(self.read_timeout)
+            .with_write_timeout(self.write_timeout);
+        let mut served = 0u64;
+        let mut stopping = self.stop_signal.subscribe();
+
+        loop {
+            if *stopping.borrow() {
+                break;
+            }
+            // A stop only interrupts the wait for a request, an
+            // unanswered request is delivered again by telegram
+            let request = tokio::select! {
+                request = connection.read_request() => request,
+                _ = stopping.changed() => break,
+            };
+            let request = match request {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(RequestError::Io(why)) => return Err(why.into()),
+                Err(why) => {
+                    warn!(self.logger, "Refused an invalid webhook request: {}", why;
+                        "peer" => peer.clone()
+                    );
+                    let status = match why {
+                        RequestError::PayloadTooLarge(_) => http::StatusCode::PAYLOAD_TOO_LARGE,
+                        RequestError::Timeout => http::StatusCode::REQUEST_TIMEOUT,
+                        _ => http::StatusCode::BAD_REQUEST,
+                    };
+                    // The rest of the stream cannot be trusted
+                    // to start with a new request anymore
+                    let mut response = status_response(status, "invalid request");
+                    set_connection_header(&mut response, false);
+                    connection.write_response(response).await?;
+                    break;
+                }
+            };
+            served += 1;
+            let keep_alive = wants_keep_alive(&request) && !*stopping.borrow();
+            match self.route(&request, &peer) {
+                Ok(update) => {
+                    let mut response = status_response(http::StatusCode::OK, "ok");
+                    set_connection_header(&mut response, keep_alive);
+                    connection.write_response(response).await?;
+                    if let Err(why) = self.dispatcher.dispatch(update).await {
+                        error!(self.logger, "Could not dispatch an update: {:#?}", why);
+                    }
+                }
+                Err(mut response) => {
+                    set_connection_header(&mut response, keep_alive);
+                    connection.write_response(response).await?;
+                }
+            }
+            if !keep_alive {
+                break;
+            }
+        }
+        debug!(self.logger, "Webhook connection closed";
+            "peer" => peer,
+            "requests" => served
+        );
+        Ok(())
+    }
+
+    fn request_stop(&self) {
+        self.stop_signal.send_replace(true);
+    }
+
+    async fn reject_stream(&self, stream: TcpStream) -> UResult {
+        let peer = stream.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+        warn!(self.logger, "Too many webhook connections, refusing a new one";
+            "peer" => peer.clone()
+        );
+        // A refused client gets little time to complete the
+        // handshake, slow ones are not worth the slot
+        let timeout = self.read_timeout.min(REJECT_HANDSHAKE_TIMEOUT);
+        let stream = match self.accept_tls(stream, &peer, timeout).await? {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        // The request itself is never read, telegram sends it
+        // again after the delay
+        let mut connection =
+            HttpConnection::new(stream, MAX_UPDATE_SIZE).with_write_timeout(self.write_timeout);
+        let mut response = status_response(http::StatusCode::SERVICE_UNAVAILABLE, "busy");
+        response.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from(self.retry_after.as_secs()),
+        );
+        set_connection_header(&mut response, false);
+        connection.write_response(response).await?;
         Ok(())
     }
 }
+
+/// Tell the client whether the connection stays open
+fn set_connection_header(response: &mut http::Response<String>, keep_alive: bool) {
+    let value = if keep_alive { "keep-alive" } else { "close" };
+    response.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static(value),
+    );
+}
@@ -0,0 +1,352 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Maximal size of the head of a request (request line
+/// and headers)
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Maximal number of headers of a request
+const MAX_HEADERS: usize = 64;
+
+/// Error occuring while reading an HTTP request
+#[derive(Debug)]
+pub enum RequestError {
+    /// The request does not follow the HTTP/1.1 syntax
+    Malformed(String),
+    /// The body of the request is larger than allowed
+    PayloadTooLarge(usize),
+    /// The request was not received in time
+    Timeout,
+    /// The underlying stream failed
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Malformed(why) => write!(f, "Malformed HTTP request: {}", why),
+            RequestError::PayloadTooLarge(size) => {
+                write!(f, "HTTP request body too large: {} bytes", size)
+            }
+            RequestError::Timeout => write!(f, "HTTP request not received in time"),
+            RequestError::Io(why) => write!(f, "I/O error while reading a request: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<std::io::Error> for RequestError {
+    fn from(why: std::io::Error) -> Self {
+        RequestError::Io(why)
+    }
+}
+
+/// HTTP/1.1 connection over a stream, reading requests with
+/// bounded sizes and writing responses
+pub struct HttpConnection<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    max_body_size: usize,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> HttpConnection<S> {
+    /// Wrap the stream, bodies larger than `max_body_size` bytes
+    /// are refused
+    pub fn new(stream: S, max_body_size: usize) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            max_body_size,
+            idle_timeout: None,
+            request_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    /// Close the connection when no request starts within the
+    /// given time
+    pub fn with_idle_timeout(self, timeout: Duration) -> Self {
+        Self {
+            idle_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Limit the time between the first byte of a request and
+    /// its last one
+    pub fn with_request_timeout(self, timeout: Duration) -> Self {
+        Self {
+            request_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Limit the time allowed to write a whole response
+    pub fn with_write_timeout(self, timeout: Duration) -> Self {
+        Self {
+            write_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Read more data from the stream into the buffer before the
+    /// deadline, returns false if the stream was closed
+    async fn fill_buffer(&mut self, deadline: Option<Instant>) -> Result<bool, RequestError> {
+        let mut chunk = [0u8; 4096];
+        let read = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, self.stream.read(&mut chunk))
+                .await
+                .map_err(|_| RequestError::Timeout)??,
+            None => self.stream.read(&mut chunk).await?,
+        };
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    /// Wait for the first bytes of the next request, returns false
+    /// if the connection was closed or stayed idle for too long
+    async fn await_request(&mut self) -> Result<bool, RequestError> {
+        if !self.buffer.is_empty() {
+            return Ok(true);
+        }
+        let deadline = self.idle_timeout.map(|t| Instant::now() + t);
+        match self.fill_buffer(deadline).await {
+            Err(RequestError::Timeout) => Ok(false),
+            result => result,
+        }
+    }
+
+    /// Read the next request, returns `None` if the peer closed
+    /// the connection or stayed idle before sending anything,
+    /// pipelined requests stay buffered for the next calls
+    pub async fn read_request(&mut self) -> Result<Option<http::Request<Vec<u8>>>, RequestError> {
+        if !self.await_request().await? {
+            return Ok(None);
+        }
+        let deadline = self.request_timeout.map(|t| Instant::now() + t);
+        let head_len = loop {
+            if let Some(pos) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(RequestError::Malformed("request head too large".into()));
+            }
+            if !self.fill_buffer(deadline).await? {
+                return Err(RequestError::Malformed("connection closed mid-request".into()));
+            }
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        parsed
+            .parse(&self.buffer[..head_len])
+            .map_err(|why| RequestError::Malformed(why.to_string()))?;
+
+        let version = match parsed.version {
+            Some(0) => http::Version::HTTP_10,
+            _ => http::Version::HTTP_11,
+        };
+        let mut builder = http::Request::builder()
+            .method(parsed.method.unwrap_or_default())
+            .uri(parsed.path.unwrap_or_default())
+            .version(version);
+        let mut content_length = 0usize;
+        for header in parsed.headers.iter() {
+            if header.name.eq_ignore_ascii_case("Content-Length") {
+                content_length = std::str::from_utf8(header.value)
+                    .ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .ok_or_else(|| RequestError::Malformed("invalid Content-Length".into()))?;
+            }
+            if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+                return Err(RequestError::Malformed("chunked bodies are not supported".into()));
+            }
+            builder = builder.header(header.name, header.value);
+        }
+        if content_length > self.max_body_size {
+            return Err(RequestError::PayloadTooLarge(content_length));
+        }
+
+        while self.buffer.len() < head_len + content_length {
+            if !self.fill_buffer(deadline).await? {
+                return Err(RequestError::Malformed("connection closed mid-body".into()));
+            }
+        }
+        let body = self.buffer[head_len..head_len + content_length].to_vec();
+        self.buffer.drain(..head_len + content_length);
+
+        builder
+            .body(body)
+            .map(Some)
+            .map_err(|why| RequestError::Malformed(why.to_string()))
+    }
+
+    /// Write the response, the `Content-Length` header is set
+    /// from the body
+    pub async fn write_response(
+        &mut self,
+        response: http::Response<String>,
+    ) -> std::io::Result<()> {
+        match self.write_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.send_response(response))
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?,
+            None => self.send_response(response).await,
+        }
+    }
+
+    async fn send_response(&mut self, response: http::Response<String>) -> std::io::Result<()> {
+        let status = response.status();
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default()
+        );
+        for (name, value) in response.headers() {
+            head.push_str(&format!("{}: {}\r\n", name, value.to_str().unwrap_or_default()));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", response.body().len()));
+
+        self.stream.write_all(head.as_bytes()).await?;
+        self.stream.write_all(response.body().as_bytes()).await?;
+        self.stream.flush().await
+    }
+}
+
+/// Check whether the client wants the connection to stay open
+/// after the request, which is the default since HTTP/1.1
+pub fn wants_keep_alive<T>(request: &http::Request<T>) -> bool {
+    let connection = request
+        .headers()
+        .get(http::header::CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_ascii_lowercase());
+    match (request.version(), connection.as_deref()) {
+        (_, Some(v)) if v.contains("close") => false,
+        (_, Some(v)) if v.contains("keep-alive") => true,
+        (version, _) => version >= http::Version::HTTP_11,
+    }
+}
+
+/// JSON response with the given status, the body only holds
+/// a short description of the result
+pub fn status_response(status: http::StatusCode, result: &str) -> http::Response<String> {
+    http::Response::builder()
+        .version(http::Version::HTTP_11)
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(format!(r#"{{"result":"{}"}}"#, result))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    /// Connection reading the given bytes, the peer is closed
+    /// once they are sent
+    async fn connection(input: &[u8], max_body_size: usize) -> HttpConnection<DuplexStream> {
+        let (mut client, server) = duplex(64 * 1024);
+        client.write_all(input).await.unwrap();
+        drop(client);
+        HttpConnection::new(server, max_body_size)
+    }
+
+    #[tokio::test]
+    async fn reads_a_request_with_a_body() {
+        let mut conn = connection(
+            b"POST /hook HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\n{\"a\"",
+            1024,
+        )
+        .await;
+        let request = conn.read_request().await.unwrap().unwrap();
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(request.uri(), "/hook");
+        assert_eq!(request.version(), http::Version::HTTP_11);
+        assert_eq!(request.headers()["host"], "x");
+        assert_eq!(request.body(), b"{\"a\"");
+        assert!(conn.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_requests() {
+        let mut conn = connection(
+            b"POST /a HTTP/1.1\r\nContent-Length: 1\r\n\r\nxGET /b HTTP/1.0\r\n\r\n",
+            1024,
+        )
+        .await;
+        let first = conn.read_request().await.unwrap().unwrap();
+        assert_eq!((first.uri().path(), first.body().as_slice()), ("/a", &b"x"[..]));
+        let second = conn.read_request().await.unwrap().unwrap();
+        assert_eq!(second.uri(), "/b");
+        assert_eq!(second.version(), http::Version::HTTP_10);
+        assert!(second.body().is_empty());
+        assert!(conn.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_large_bodies_before_reading_them() {
+        let mut conn = connection(b"POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n", 1024).await;
+        assert!(matches!(
+            conn.read_request().await,
+            Err(RequestError::PayloadTooLarge(2048))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_chunked_and_malformed_requests() {
+        let mut conn = connection(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n", 1024).await;
+        assert!(matches!(conn.read_request().await, Err(RequestError::Malformed(_))));
+
+        let mut conn = connection(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n", 1024).await;
+        assert!(matches!(conn.read_request().await, Err(RequestError::Malformed(_))));
+
+        let mut conn = connection(b"NOT HTTP\r\n\r\n", 1024).await;
+        assert!(matches!(conn.read_request().await, Err(RequestError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn reports_truncated_requests() {
+        let mut conn = connection(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc", 1024).await;
+        assert!(matches!(conn.read_request().await, Err(RequestError::Malformed(_))));
+
+        let mut conn = connection(b"POST / HTTP/1.1\r\nHost:", 1024).await;
+        assert!(matches!(conn.read_request().await, Err(RequestError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let (_client, server) = duplex(1024);
+        let mut conn = HttpConnection::new(server, 1024).with_idle_timeout(Duration::from_millis(10));
+        assert!(conn.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_requests() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"POST / HTTP/1.1\r\n").await.unwrap();
+        let mut conn = HttpConnection::new(server, 1024).with_request_timeout(Duration::from_millis(10));
+        assert!(matches!(conn.read_request().await, Err(RequestError::Timeout)));
+    }
+
+    #[test]
+    fn keep_alive_follows_the_version_and_header() {
+        let request = |version, connection: Option<&str>| {
+            let mut builder = http::Request::builder().version(version);
+            if let Some(connection) = connection {
+                builder = builder.header("Connection", connection);
+            }
+            builder.body(()).unwrap()
+        };
+        assert!(wants_keep_alive(&request(http::Version::HTTP_11, None)));
+        assert!(!wants_keep_alive(&request(http::Version::HTTP_11, Some("close"))));
+        assert!(!wants_keep_alive(&request(http::Version::HTTP_10, None)));
+        assert!(wants_keep_alive(&request(http::Version::HTTP_10, Some("Keep-Alive"))));
+    }
+}
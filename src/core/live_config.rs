@@ -0,0 +1,108 @@
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+
+use crate::config::Config;
+use crate::prelude::*;
+
+/// Configuration of the running application along with the
+/// services derived from it
+#[derive(Debug)]
+pub struct ConfigSnapshot {
+    pub config: Config,
+    pub routes: RoutingTable,
+    pub discord_sender: Option<Arc<CommandSender>>,
+}
+
+impl ConfigSnapshot {
+    fn new(config: Config) -> Self {
+        let routes = RoutingTable::from_config(&config);
+        let discord_sender = config
+            .integrations
+            .as_ref()
+            .and_then(|i| i.discord.as_ref())
+            .map(|addr| Arc::new(CommandSender::new(addr.to_string_lossy().into_owned())));
+        Self {
+            config,
+            routes,
+            discord_sender,
+        }
+    }
+}
+
+/// Shared handle to the live configuration, readers keep a
+/// consistent snapshot while a reload swaps in a new one
+#[derive(Debug, Clone)]
+pub struct ConfigHandle {
+    current: Arc<RwLock<Arc<ConfigSnapshot>>>,
+}
+
+impl ConfigHandle {
+    /// Instantiate a handle to the given configuration
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(ConfigSnapshot::new(config)))),
+        }
+    }
+
+    /// Current configuration
+    pub fn snapshot(&self) -> Arc<ConfigSnapshot> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Swap in a new configuration, returns the previous one
+    pub fn replace(&self, config: Config) -> Arc<ConfigSnapshot> {
+        let snapshot = Arc::new(ConfigSnapshot::new(config));
+        std::mem::replace(&mut *self.current.write().unwrap(), snapshot)
+    }
+}
+
+fn differs<T: Serialize>(old: &T, new: &T) -> bool {
+    serde_json::to_value(old).ok() != serde_json::to_value(new).ok()
+}
+
+/// Names of the changed settings which only take effect after a
+/// restart, the bridge routes and the integrations are the only
+/// ones applied on the fly
+pub fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let (old_general, new_general) = (&old.general, &new.general);
+    let checks = [
+        ("general.server_ip", differs(&old_general.server_ip, &new_general.server_ip)),
+        ("general.server_port", differs(&old_general.server_port, &new_general.server_port)),
+        (
+            "general.private_key_path",
+            differs(&old_general.private_key_path, &new_general.private_key_path),
+        ),
+        (
+            "general.certificate_path",
+            differs(&old_general.certificate_path, &new_general.certificate_path),
+        ),
+        ("general.token_var", differs(&old_general.token_var, &new_general.token_var)),
+        ("general.token_file", differs(&old_general.token_file, &new_general.token_file)),
+        (
+            "general.token_command",
+            differs(&old_general.token_command, &new_general.token_command),
+        ),
+        ("general.sock_addr", differs(&old_general.sock_addr, &new_general.sock_addr)),
+        ("general.mode", differs(&old_general.mode, &new_general.mode)),
+        ("general.webhook_path", differs(&old_general.webhook_path, &new_general.webhook_path)),
+        (
+            "general.shutdown_timeout",
+            differs(&old_general.shutdown_timeout, &new_general.shutdown_timeout),
+        ),
+        (
+            "general.webhook_secret",
+            differs(&old_general.webhook_secret, &new_general.webhook_secret),
+        ),
+        ("server", differs(&old.server, &new.server)),
+        ("supervisor", differs(&old.supervisor, &new.supervisor)),
+        ("webhook", differs(&old.webhook, &new.webhook)),
+        ("polling", differs(&old.polling, &new.polling)),
+        ("storage", differs(&old.storage, &new.storage)),
+        ("media", differs(&old.media, &new.media)),
+    ];
+    checks
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+}
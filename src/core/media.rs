@@ -0,0 +1,324 @@
+use slog::Logger;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use telegram_bot_api::bot::BotApi;
+use telegram_bot_api::methods::GetFile;
+use telegram_bot_api::types::{InputFile, Message};
+use zeroize::Zeroizing;
+
+use crate::prelude::*;
+
+/// Description of a file attached to a telegram message
+#[derive(Debug, Clone)]
+pub struct TelegramMedia {
+    pub file_id: String,
+    pub file_unique_id: String,
+    pub file_name: String,
+    pub mime_type: Option<String>,
+    pub size: Option<u64>,
+}
+
+impl TelegramMedia {
+    fn new(
+        file_id: &str,
+        file_unique_id: &str,
+        file_name: String,
+        mime_type: Option<String>,
+        size: Option<i64>,
+    ) -> Self {
+        Self {
+            file_id: file_id.to_owned(),
+            file_unique_id: file_unique_id.to_owned(),
+            file_name,
+            mime_type,
+            size: size.map(|v| v as u64),
+        }
+    }
+
+    /// Extract the file attached to a photo, document, audio,
+    /// voice, video, animation or sticker message
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        if let Some(ref photo) = msg.photo {
+            // Telegram sends several sizes of the same photo,
+            // the largest one is the last
+            let largest = photo.last()?;
+            return Some(Self::new(
+                &largest.file_id,
+                &largest.file_unique_id,
+                format!("{}.jpg", largest.file_unique_id),
+                Some("image/jpeg".to_owned()),
+                largest.file_size,
+            ));
+        }
+        if let Some(ref animation) = msg.animation {
+            return Some(Self::new(
+                &animation.file_id,
+                &animation.file_unique_id,
+                animation
+                    .file_name
+                    .clone()
+                    .unwrap_or(format!("{}.mp4", animation.file_unique_id)),
+                animation.mime_type.clone(),
+                animation.file_size,
+            ));
+        }
+        if let Some(ref document) = msg.document {
+            return Some(Self::new(
+                &document.file_id,
+                &document.file_unique_id,
+                document
+                    .file_name
+                    .clone()
+                    .unwrap_or(document.file_unique_id.clone()),
+                document.mime_type.clone(),
+                document.file_size,
+            ));
+        }
+        if let Some(ref audio) = msg.audio {
+            return Some(Self::new(
+                &audio.file_id,
+                &audio.file_unique_id,
+                audio
+                    .file_name
+                    .clone()
+                    .unwrap_or(format!("{}.mp3", audio.file_unique_id)),
+                audio.mime_type.clone(),
+                audio.file_size,
+            ));
+        }
+        if let Some(ref voice) = msg.voice {
+            return Some(Self::new(
+                &voice.file_id,
+                &voice.file_unique_id,
+                format!("{}.ogg", voice.file_unique_id),
+                voice.mime_type.clone().or(Some("audio/ogg".to_owned())),
+                voice.file_size,
+            ));
+        }
+        if let Some(ref video) = msg.video {
+            return Some(Self::new(
+                &video.file_id,
+                &video.file_unique_id,
+                video
+                    .file_name
+                    .clone()
+                    .unwrap_or(format!("{}.mp4", video.file_unique_id)),
+                video.mime_type.clone(),
+                video.file_size,
+            ));
+        }
+        if let Some(ref sticker) = msg.sticker {
+            let (extension, mime_type) = match (sticker.is_animated, sticker.is_video) {
+                (true, _) => ("tgs", "application/x-tgsticker"),
+                (_, true) => ("webm", "video/webm"),
+                _ => ("webp", "image/webp"),
+            };
+            return Some(Self::new(
+                &sticker.file_id,
+                &sticker.file_unique_id,
+                format!("{}.{}", sticker.file_unique_id, extension),
+                Some(mime_type.to_owned()),
+                sticker.file_size,
+            ));
+        }
+        None
+    }
+}
+
+/// Maximal age of a downloaded file before it gets purged,
+/// the discord bot is expected to upload it way earlier
+const DOWNLOAD_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Interval between two purges of the outdated downloads
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Service downloading files attached to telegram messages
+/// into a local directory shared with the other bots
+pub struct MediaDownloader {
+    tgbot: Arc<BotApi>,
+    client: reqwest::Client,
+    file_url: Zeroizing<String>,
+    download_dir: PathBuf,
+    logger: Logger,
+}
+
+impl std::fmt::Debug for MediaDownloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MediaDownloader")
+            .field("download_dir", &self.download_dir)
+            .finish()
+    }
+}
+
+impl MediaDownloader {
+    /// Instantiate a new downloader storing files in the given
+    /// directory, which is created if needed and purged of
+    /// outdated downloads
+    pub fn new(
+        tgbot: Arc<BotApi>,
+        token: &str,
+        download_dir: &Path,
+        logger: Logger,
+    ) -> UResult<Self> {
+        std::fs::create_dir_all(download_dir)?;
+        let downloader = Self {
+            tgbot,
+            client: reqwest::Client::new(),
+            file_url: Zeroizing::new(format!("https://api.telegram.org/file/bot{}", token)),
+            download_dir: download_dir.to_owned(),
+            logger,
+        };
+        downloader.purge()?;
+        Ok(downloader)
+    }
+
+    /// Remove the downloads older than a day
+    pub fn purge(&self) -> UResult {
+        for entry in std::fs::read_dir(&self.download_dir)? {
+            let entry = entry?;
+            let age = entry
+                .metadata()?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            if age > DOWNLOAD_MAX_AGE {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Purge the outdated downloads every hour until the
+    /// application stops
+    pub async fn purge_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        // The first tick is immediate, the directory was purged
+        // when the downloader was created
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let downloader = self.clone();
+            let result = tokio::task::spawn_blocking(move || downloader.purge()).await;
+            match result {
+                Ok(Ok(())) => debug!(self.logger, "Purged the outdated downloads"),
+                Ok(Err(why)) => warn!(self.logger, "Could not purge the outdated downloads: {:#?}", why),
+                Err(why) => warn!(self.logger, "Could not purge the outdated downloads: {:#?}", why),
+            }
+        }
+    }
+
+    /// Fetch the file through `getFile` and save it locally, the
+    /// resulting attachment points to the downloaded file
+    pub async fn download(&self, media: &TelegramMedia) -> UResult<Attachment> {
+        let file = match self.tgbot.get_file(GetFile::new(media.file_id.clone())).await {
+            Ok(file) => file,
+            Err(why) => return Err(format!("getFile request failed: {:#?}", why).into()),
+        };
+        let remote_path = file
+            .file_path
+            .ok_or("Telegram did not provide a path for the file")?;
+
+        // The download url contains the bot token, it must not
+        // leak into the logs through the returned errors
+        let url = Zeroizing::new(format!("{}/{}", self.file_url.as_str(), remote_path));
+        let response = self
+            .client
+            .get(url.as_str())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.without_url())?;
+        let bytes = response.bytes().await.map_err(|e| e.without_url())?;
+
+        let file_name = media.file_name.replace(std::path::MAIN_SEPARATOR, "_");
+        let local_path = self
+            .download_dir
+            .join(format!("{}_{}", media.file_unique_id, file_name));
+        tokio::fs::write(&local_path, &bytes).await?;
+        debug!(self.logger, "Downloaded a telegram file";
+            "path" => local_path.to_string_lossy().into_owned(),
+            "size" => bytes.len()
+        );
+
+        Ok(Attachment {
+            file_name: media.file_name.clone(),
+            mime_type: media.mime_type.clone(),
+            size: Some(bytes.len() as u64),
+            source: AttachmentSource::Path(local_path),
+        })
+    }
+}
+
+/// Maximal size of a photo uploaded by a bot
+const PHOTO_UPLOAD_LIMIT: u64 = 10 * 1024 * 1024;
+/// Maximal size of any other file uploaded by a bot
+const FILE_UPLOAD_LIMIT: u64 = 50 * 1024 * 1024;
+/// Maximal size of a photo telegram fetches by url
+const PHOTO_URL_LIMIT: u64 = 5 * 1024 * 1024;
+/// Maximal size of any other file telegram fetches by url
+const FILE_URL_LIMIT: u64 = 20 * 1024 * 1024;
+
+/// Telegram method used to post an attachment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadKind {
+    Photo,
+    Video,
+    Audio,
+    Document,
+}
+
+impl UploadKind {
+    /// Choose the upload method from the MIME type of the
+    /// attachment, unknown types are sent as documents
+    pub fn of(attachment: &Attachment) -> Self {
+        let mime_type = attachment.mime_type.as_deref().unwrap_or_default();
+        match mime_type.split('/').next().unwrap_or_default() {
+            // Animated images lose their animation when sent as photos
+            "image" if mime_type != "image/gif" && mime_type != "image/webp" => UploadKind::Photo,
+            "video" => UploadKind::Video,
+            "audio" => UploadKind::Audio,
+            _ => UploadKind::Document,
+        }
+    }
+
+    /// Whether attachments of this kind can be sent together
+    /// through `sendMediaGroup` with the other visual media
+    pub fn is_visual(&self) -> bool {
+        matches!(self, UploadKind::Photo | UploadKind::Video)
+    }
+}
+
+/// Check whether the attachment is too large for telegram to
+/// accept it, attachments of unknown size are assumed to fit
+pub fn exceeds_upload_limit(attachment: &Attachment) -> bool {
+    let size = match attachment.size {
+        Some(size) => size,
+        None => return false,
+    };
+    let is_photo = UploadKind::of(attachment) == UploadKind::Photo;
+    let limit = match (&attachment.source, is_photo) {
+        (AttachmentSource::Url(_), true) => PHOTO_URL_LIMIT,
+        (AttachmentSource::Url(_), false) => FILE_URL_LIMIT,
+        (_, true) => PHOTO_UPLOAD_LIMIT,
+        (_, false) => FILE_UPLOAD_LIMIT,
+    };
+    size > limit
+}
+
+/// Convert a qcproto attachment into a telegram input file
+pub fn input_file(attachment: &Attachment) -> UResult<InputFile> {
+    match attachment.source {
+        AttachmentSource::Path(ref path) => load_input_file(&path.to_string_lossy()),
+        AttachmentSource::Url(ref url) => Ok(InputFile::FileURL(url.clone())),
+    }
+}
+
+/// Human readable link replacing an attachment which could
+/// not be uploaded to telegram
+pub fn attachment_link(attachment: &Attachment) -> String {
+    match attachment.source {
+        AttachmentSource::Url(ref url) => format!("📎 {}: {}", attachment.file_name, url),
+        _ => format!("📎 {} (too large to be forwarded)", attachment.file_name),
+    }
+}
@@ -1,10 +1,26 @@
 pub mod application;
 mod common;
 mod dispatchers;
+mod formatting;
 mod handlers;
+mod http_connection;
+mod live_config;
+mod media;
+mod polling;
+mod routing;
 mod servers;
+mod storage;
+mod supervisor;
 
 pub use common::*;
 pub use dispatchers::*;
+pub use formatting::*;
 pub use handlers::*;
+pub use http_connection::*;
+pub use live_config::*;
+pub use media::*;
+pub use polling::*;
+pub use routing::*;
 pub use servers::*;
+pub use storage::*;
+pub use supervisor::*;
@@ -0,0 +1,203 @@
+use slog::Logger;
+use telegram_bot_api::bot::BotApi;
+use telegram_bot_api::methods::{DeleteWebhook, GetUpdates};
+
+use crate::prelude::*;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Delay before retrying after a failed `getUpdates` request
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Update source pulling telegram updates with long polling
+/// `getUpdates` requests, an alternative to the webhook server
+/// which does not require a public TLS endpoint
+pub struct UpdatePoller {
+    tgbot: Arc<BotApi>,
+    dispatcher: Arc<dyn UpdateDispatcher>,
+    offset_path: PathBuf,
+    timeout: u32,
+    stop_requested: AtomicBool,
+    stop_notify: Notify,
+    logger: Logger,
+}
+
+/// Builder type allowing to configure and instantiate
+/// an update poller
+#[derive(Default)]
+pub struct UpdatePollerBuilder {
+    tgbot: Option<Arc<BotApi>>,
+    dispatcher: Option<Arc<dyn UpdateDispatcher>>,
+    offset_path: Option<PathBuf>,
+    timeout: Option<u32>,
+    logger: Option<Logger>,
+}
+
+impl UpdatePollerBuilder {
+    /// Set the telegram bot handle used to fetch updates
+    pub fn bot(self, tgbot: Arc<BotApi>) -> Self {
+        Self {
+            tgbot: Some(tgbot),
+            ..self
+        }
+    }
+
+    /// Set the dispatcher of the received updates
+    pub fn dispatcher(self, dispatcher: Arc<dyn UpdateDispatcher>) -> Self {
+        Self {
+            dispatcher: Some(dispatcher),
+            ..self
+        }
+    }
+
+    /// Set the file persisting the update offset across restarts
+    pub fn offset_path(self, path: &Path) -> Self {
+        Self {
+            offset_path: Some(path.to_owned()),
+            ..self
+        }
+    }
+
+    /// Set the duration of a single long polling request in seconds
+    pub fn timeout(self, timeout: u32) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Set the integrated logger
+    pub fn logger(self, logger: Logger) -> Self {
+        Self {
+            logger: Some(logger),
+            ..self
+        }
+    }
+
+    /// Finalize the instantiation of an update poller
+    pub fn build(self) -> UpdatePoller {
+        assert!(
+            self.logger.is_some(),
+            "Did not provide a logger for the update poller"
+        );
+        assert!(
+            self.tgbot.is_some(),
+            "Did not provide the telegram bot handle for the update poller"
+        );
+        assert!(
+            self.dispatcher.is_some(),
+            "Did not provide an update dispatcher for the update poller"
+        );
+        assert!(
+            self.offset_path.is_some(),
+            "Did not provide an offset file path for the update poller"
+        );
+
+        UpdatePoller {
+            tgbot: self.tgbot.unwrap(),
+            dispatcher: self.dispatcher.unwrap(),
+            offset_path: self.offset_path.unwrap(),
+            timeout: self.timeout.unwrap_or(30),
+            stop_requested: AtomicBool::new(false),
+            stop_notify: Notify::new(),
+            logger: self.logger.unwrap(),
+        }
+    }
+}
+
+impl UpdatePoller {
+    /// Instantiate a new update poller
+    pub fn new() -> UpdatePollerBuilder {
+        Default::default()
+    }
+
+    fn load_offset(&self) -> UResult<Option<i64>> {
+        if !self.offset_path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&self.offset_path)?;
+        Ok(Some(contents.trim().parse::<i64>()?))
+    }
+
+    fn save_offset(&self, offset: i64) -> UResult {
+        std::fs::write(&self.offset_path, format!("{}", offset))?;
+        Ok(())
+    }
+
+    /// Remove the registered webhook, telegram refuses `getUpdates`
+    /// requests while a webhook is active
+    async fn delete_webhook(&self) -> UResult {
+        match self.tgbot.delete_webhook(DeleteWebhook::new()).await {
+            Ok(_) => {
+                info!(self.logger, "Webhook deleted, switching to long polling");
+                Ok(())
+            }
+            Err(why) => Err(format!("Could not delete the webhook: {:#?}", why).into()),
+        }
+    }
+
+    /// Poll for updates and dispatch them until a stop is
+    /// requested
+    pub async fn listen(&self) -> UResult {
+        self.delete_webhook().await?;
+        let mut offset = self.load_offset()?;
+        info!(self.logger, "Listening for telegram updates with long polling";
+            "offset" => offset
+        );
+
+        while !self.is_stopped() {
+            let request = {
+                let mut request = GetUpdates::new();
+                request.offset = offset;
+                request.timeout = Some(self.timeout as i64);
+                request
+            };
+            // Abandoning a pending request is harmless, its updates
+            // are fetched again on the next start
+            let updates = tokio::select! {
+                updates = self.tgbot.get_updates(request) => updates,
+                _ = self.stop_notify.notified() => break,
+            };
+            let updates = match updates {
+                Ok(updates) => updates,
+                Err(why) => {
+                    error!(self.logger, "Could not fetch updates; reason: {:#?}", why);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            for update in updates {
+                let update_id = update.update_id;
+                if let Err(why) = self.dispatcher.dispatch(update).await {
+                    error!(self.logger, "Could not dispatch an update: {:#?}", why;
+                        "update_id" => update_id
+                    );
+                }
+                offset = Some(update_id as i64 + 1);
+            }
+            if let Some(offset) = offset {
+                if let Err(why) = self.save_offset(offset) {
+                    warn!(self.logger, "Could not persist the update offset: {:#?}", why);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Request the poller to stop, the updates being dispatched
+    /// are completed first
+    pub fn request_stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.stop_notify.notify_one();
+    }
+
+    /// Check whether a stop was requested
+    pub fn is_stopped(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+}
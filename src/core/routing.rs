@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use crate::config::Config;
+
+/// Address of a discord text channel taking part
+/// in the bridge
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DiscordChannel {
+    pub server: String,
+    pub channel: String,
+}
+
+/// Bidirectional routing table between telegram chats
+/// and discord channels built from the `[[bridge]]`
+/// config entries
+#[derive(Debug, Default, Clone)]
+pub struct RoutingTable {
+    to_discord: HashMap<i64, Vec<DiscordChannel>>,
+    to_telegram: HashMap<DiscordChannel, Vec<i64>>,
+}
+
+impl RoutingTable {
+    /// Build the routing table from the bridge section
+    /// of the application config
+    pub fn from_config(config: &Config) -> Self {
+        let mut table = Self::default();
+        for route in config.bridge.iter() {
+            for channel in route.discord_channels.iter() {
+                let target = DiscordChannel {
+                    server: route.discord_server.clone(),
+                    channel: channel.clone(),
+                };
+                table.link(route.telegram_chat, target);
+            }
+        }
+        table
+    }
+
+    fn link(&mut self, chat_id: i64, target: DiscordChannel) {
+        let channels = self.to_discord.entry(chat_id).or_default();
+        if !channels.contains(&target) {
+            channels.push(target.clone());
+        }
+        let chats = self.to_telegram.entry(target).or_default();
+        if !chats.contains(&chat_id) {
+            chats.push(chat_id);
+        }
+    }
+
+    /// Discord channels receiving messages from the given
+    /// telegram chat, empty if the chat is not bridged
+    pub fn discord_targets(&self, chat_id: i64) -> &[DiscordChannel] {
+        self.to_discord
+            .get(&chat_id)
+            .map(|v| v.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Telegram chats receiving messages from the given
+    /// discord channel, empty if the channel is not bridged
+    pub fn telegram_targets(&self, server: &str, channel: &str) -> &[i64] {
+        let key = DiscordChannel {
+            server: server.to_owned(),
+            channel: channel.to_owned(),
+        };
+        self.to_telegram
+            .get(&key)
+            .map(|v| v.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BridgeSection;
+
+    fn route(chat: i64, server: &str, channels: &[&str]) -> BridgeSection {
+        BridgeSection {
+            telegram_chat: chat,
+            discord_server: server.to_owned(),
+            discord_channels: channels.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn table(routes: Vec<BridgeSection>) -> RoutingTable {
+        let config = Config {
+            bridge: routes,
+            ..Config::default()
+        };
+        RoutingTable::from_config(&config)
+    }
+
+    fn channel(server: &str, channel: &str) -> DiscordChannel {
+        DiscordChannel {
+            server: server.to_owned(),
+            channel: channel.to_owned(),
+        }
+    }
+
+    #[test]
+    fn routes_both_ways() {
+        let table = table(vec![route(-1, "s", &["a", "b"])]);
+        assert_eq!(table.discord_targets(-1), [channel("s", "a"), channel("s", "b")]);
+        assert_eq!(table.telegram_targets("s", "a"), [-1]);
+        assert_eq!(table.telegram_targets("s", "b"), [-1]);
+    }
+
+    #[test]
+    fn unknown_chats_and_channels_have_no_targets() {
+        let table = table(vec![route(-1, "s", &["a"])]);
+        assert!(table.discord_targets(-2).is_empty());
+        assert!(table.telegram_targets("s", "b").is_empty());
+        // Channels are only known within their own server
+        assert!(table.telegram_targets("other", "a").is_empty());
+    }
+
+    #[test]
+    fn merges_routes_without_duplicates() {
+        let table = table(vec![
+            route(-1, "s", &["a", "a"]),
+            route(-2, "s", &["a"]),
+            route(-1, "s", &["a", "c"]),
+        ]);
+        assert_eq!(table.discord_targets(-1), [channel("s", "a"), channel("s", "c")]);
+        assert_eq!(table.telegram_targets("s", "a"), [-1, -2]);
+    }
+}
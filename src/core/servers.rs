@@ -2,29 +2,41 @@ use slog::Logger;
 
 use crate::prelude::*;
 
-use std::net::{TcpListener, TcpStream};
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, Semaphore};
+
+/// Number of connections over the limit which are answered
+/// at once, the others are dropped without an answer
+const REJECT_SLOTS: usize = 8;
 
 /// Struct containing all the data to run the server
 /// managing Telegram update webhook requests
 pub struct UpdateServer {
-    stream_listener: Arc<dyn StreamListenerExt<TcpListener>>,
+    listener: TcpListener,
+    stream_handler: Arc<dyn AsyncStreamHandler<TcpStream>>,
+    connection_slots: Arc<Semaphore>,
+    reject_slots: Arc<Semaphore>,
+    max_connections: usize,
+    stop_requested: AtomicBool,
+    stop_notify: Notify,
+    logger: Logger,
 }
 
 /// Builder type for the construction of an update
 /// server
 #[derive(Default)]
 pub struct UpdateServerBuilder {
-    stream_handler: Option<Arc<dyn StreamHandler<TcpStream>>>,
-    stream_listener: Option<Arc<dyn StreamListenerExt<TcpListener>>>,
+    stream_handler: Option<Arc<dyn AsyncStreamHandler<TcpStream>>>,
+    max_connections: Option<usize>,
     logger: Option<Logger>,
     bind_addr: Option<String>,
 }
 
 impl UpdateServerBuilder {
     /// Set the address for server in the format accepted by
-    /// the standard `std::net::TcpListener` type ('IP_ADDR:PORT')
+    /// the `tokio::net::TcpListener` type ('IP_ADDR:PORT')
     pub fn server_addr(self, addr: &str) -> Self {
         Self {
             bind_addr: Some(String::from(addr)),
@@ -42,59 +54,49 @@ impl UpdateServerBuilder {
     }
 
     /// Set a handler for all established TCP connections
-    pub fn stream_handler(self, handler: Arc<dyn StreamHandler<TcpStream>>) -> Self {
+    pub fn stream_handler(self, handler: Arc<dyn AsyncStreamHandler<TcpStream>>) -> Self {
         Self {
             stream_handler: Some(handler),
             ..self
         }
     }
 
-    /// Set a custom TCP listener
-    pub fn stream_listener<ListenerT>(
-        self,
-        listener: Arc<dyn StreamListenerExt<TcpListener>>,
-    ) -> Self {
+    /// Set the maximal number of connections served at once,
+    /// the stream handler rejects the connections above it
+    pub fn max_connections(self, max_connections: usize) -> Self {
         Self {
-            stream_listener: Some(listener),
+            max_connections: Some(max_connections),
             ..self
         }
     }
 
-    /// Finalize the update server construction.
-    ///
-    /// Construction of the server fails in the following scenarios:
-    /// - A logger is not provided
-    /// - Both server address and custom stream listener are provided, or neither of them
-    /// - A custom stream handler is provided with the custom stream listener
-    /// - If an error occurs while initializing one of the subcomponents
-    pub fn build(self) -> UResult<UpdateServer> {
-        let flags = (
+    /// Finalize the update server construction by binding
+    /// the listener to the server address
+    pub async fn build(self) -> UResult<UpdateServer> {
+        assert!(
             self.logger.is_some(),
+            "Did not provide a logger for the update server"
+        );
+        assert!(
             self.bind_addr.is_some(),
-            self.stream_listener.is_some(),
+            "Did not provide an address to bind the update server to"
+        );
+        assert!(
             self.stream_handler.is_some(),
+            "Did not provide a stream handler for the update server"
         );
-        match flags {
-            (false, _, _, _) => panic!("Did not provide a logger for the update server"),
-            (_, true, true, _) | (_, false, false, _) => {
-                panic!("You have to provide either an address to bind to, or a configured listener")
-            }
-            (_, _, true, true) => {
-                panic!("A custom stream handler won't be used if you also provide a listener")
-            }
-            _ => (),
-        };
-
-        let stream_handler = self.stream_handler.unwrap();
-        let stream_listener = self.stream_listener.unwrap_or(Arc::new(
-            StreamListener::<TcpListener>::new()
-                .listener(TcpListener::bind(self.bind_addr.unwrap())?)
-                .stream_handler(stream_handler)
-                .logger(self.logger.unwrap())
-                .build(),
-        ));
-        let srv = UpdateServer { stream_listener };
-        Ok(srv)
+
+        let max_connections = self.max_connections.unwrap_or(64);
+        Ok(UpdateServer {
+            listener: TcpListener::bind(self.bind_addr.unwrap()).await?,
+            stream_handler: self.stream_handler.unwrap(),
+            connection_slots: Arc::new(Semaphore::new(max_connections)),
+            reject_slots: Arc::new(Semaphore::new(REJECT_SLOTS)),
+            max_connections,
+            stop_requested: AtomicBool::new(false),
+            stop_notify: Notify::new(),
+            logger: self.logger.unwrap(),
+        })
     }
 }
 
@@ -103,18 +105,77 @@ impl UpdateServer {
     pub fn new() -> UpdateServerBuilder {
         Default::default()
     }
-}
 
-impl StreamListenerExt<TcpListener> for UpdateServer {
-    fn listen(&self) -> UResult {
-        self.stream_listener.listen()
+    /// Accept connections until a stop is requested, each
+    /// connection is served concurrently in its own task as
+    /// long as a connection slot is available. A few of the
+    /// connections over the limit are answered with a refusal,
+    /// the rest is dropped
+    pub async fn listen(&self) -> UResult {
+        info!(self.logger, "Listening for telegram updates";
+            "addr" => self.listener.local_addr()?.to_string()
+        );
+        while !self.is_stopped() {
+            let (stream, peer) = tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(why) => {
+                        error!(self.logger, "Could not accept a connection: {:#?}", why);
+                        continue;
+                    }
+                },
+                _ = self.stop_notify.notified() => break,
+            };
+            let handler = self.stream_handler.clone();
+            let logger = self.logger.clone();
+            // Either a slot to serve the connection or one to refuse it
+            let slot = match self.connection_slots.clone().try_acquire_owned() {
+                Ok(slot) => Ok(slot),
+                Err(_) => match self.reject_slots.clone().try_acquire_owned() {
+                    Ok(slot) => Err(slot),
+                    Err(_) => {
+                        debug!(self.logger, "Too many refused connections, dropping a new one";
+                            "peer" => peer.to_string()
+                        );
+                        continue;
+                    }
+                },
+            };
+            tokio::spawn(async move {
+                // The slot is released once the connection ends
+                let result = match slot {
+                    Ok(_slot) => handler.handle_stream(stream).await,
+                    Err(_slot) => handler.reject_stream(stream).await,
+                };
+                if let Err(why) = result {
+                    warn!(logger, "Connection ended with an error: {:#?}", why;
+                        "peer" => peer.to_string()
+                    );
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Request the server to stop accepting connections and
+    /// to close the idle ones
+    pub fn request_stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.stop_notify.notify_one();
+        self.stream_handler.request_stop();
     }
 
-    fn request_stop(&self) {
-        self.stream_listener.request_stop()
+    /// Wait until every served connection is closed
+    pub async fn drain(&self) {
+        let _ = self
+            .connection_slots
+            .acquire_many(self.max_connections as u32)
+            .await;
+        let _ = self.reject_slots.acquire_many(REJECT_SLOTS as u32).await;
     }
 
-    fn is_stopped(&self) -> bool {
-        self.stream_listener.is_stopped()
+    /// Check whether a stop was requested
+    pub fn is_stopped(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
     }
 }
@@ -0,0 +1,414 @@
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use telegram_bot_api::types::Message;
+
+use crate::prelude::*;
+
+/// Location of a message on the telegram side
+/// of the bridge
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TelegramMessageRef {
+    pub chat_id: i64,
+    pub message_id: i64,
+}
+
+impl TelegramMessageRef {
+    /// Identifier of the message given to the other bots, telegram
+    /// message identifiers are only unique within their chat
+    pub fn reference(&self) -> String {
+        format!("{}:{}", self.chat_id, self.message_id)
+    }
+
+    /// Location of the message from an identifier built by
+    /// `reference`
+    pub fn from_reference(reference: &str) -> Option<Self> {
+        let (chat_id, message_id) = reference.split_once(':')?;
+        Some(Self {
+            chat_id: chat_id.parse().ok()?,
+            message_id: message_id.parse().ok()?,
+        })
+    }
+}
+
+/// Location of a message on the discord side of
+/// the bridge
+///
+/// The identifier of the message is only known for
+/// messages originating from discord, copies posted by
+/// the discord bot are addressed through the reference
+/// of their telegram original
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DiscordMessageRef {
+    pub server: String,
+    pub channel: String,
+    pub message_id: Option<String>,
+}
+
+/// Side of the bridge where a relayed message was
+/// originally posted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinkOrigin {
+    Telegram,
+    Discord,
+}
+
+/// Part of a telegram message holding its text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TextPlacement {
+    /// Text of a plain message
+    #[default]
+    Text,
+    /// Caption of a media message
+    Caption,
+    /// Media without a caption, like the other items of an album
+    Absent,
+}
+
+impl TextPlacement {
+    /// Placement of the text in the given telegram message
+    pub fn of(message: &Message) -> Self {
+        if message.caption.is_some() {
+            TextPlacement::Caption
+        } else if message.text.is_some() {
+            TextPlacement::Text
+        } else {
+            TextPlacement::Absent
+        }
+    }
+}
+
+/// Correspondence between an original message and
+/// its relayed copy on the other side of the bridge
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageLink {
+    pub origin: LinkOrigin,
+    pub telegram: TelegramMessageRef,
+    pub discord: DiscordMessageRef,
+    /// Where the telegram message holds the text, links
+    /// recorded before media were relayed are plain texts
+    #[serde(default)]
+    pub text: TextPlacement,
+    /// Unix timestamp of the moment the message was relayed
+    pub relayed_at: i64,
+}
+
+impl MessageLink {
+    /// Current unix timestamp to be used for new links
+    pub fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+}
+
+/// An interface for the storage keeping track of
+/// relayed messages
+pub trait MessageStore: Send + Sync + std::fmt::Debug {
+    /// Remember a newly relayed message
+    fn record(&self, link: MessageLink) -> UResult;
+
+    /// Find all the links involving the given telegram
+    /// message
+    fn find_by_telegram(&self, msg: &TelegramMessageRef) -> UResult<Vec<MessageLink>>;
+
+    /// Find all the links involving the given discord
+    /// message
+    fn find_by_discord(
+        &self,
+        server: &str,
+        channel: &str,
+        message_id: &str,
+    ) -> UResult<Vec<MessageLink>>;
+
+    /// Forget the links outdated according to the retention
+    /// policy of the store, returns the number of removed links
+    fn compact(&self) -> UResult<usize> {
+        Ok(0)
+    }
+}
+
+fn matches_discord(link: &MessageLink, server: &str, channel: &str, message_id: &str) -> bool {
+    link.discord.server == server
+        && link.discord.channel == channel
+        && link.discord.message_id.as_deref() == Some(message_id)
+}
+
+/// Number of appended links after which the log is
+/// compacted again
+const COMPACTION_THRESHOLD: usize = 1000;
+
+struct LogState {
+    links: Vec<MessageLink>,
+    file: File,
+    appended: usize,
+}
+
+/// File-backed message store
+///
+/// Every relayed message is appended as a JSON line to the
+/// log file, the whole log is loaded in memory on startup.
+/// Links older than the retention period are dropped from
+/// the log by rewriting it during compaction, which happens
+/// on startup and then periodically as new links are recorded
+pub struct LogMessageStore {
+    path: PathBuf,
+    retention: Duration,
+    state: Mutex<LogState>,
+    logger: Logger,
+}
+
+impl std::fmt::Debug for LogMessageStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogMessageStore")
+            .field("path", &self.path)
+            .field("retention", &self.retention)
+            .finish()
+    }
+}
+
+impl LogMessageStore {
+    /// Open the log at the given path, creating it if it does
+    /// not exist yet, and compact it
+    pub fn open(path: &Path, retention: Duration, logger: Logger) -> UResult<Self> {
+        let mut links = Vec::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<MessageLink>(&line) {
+                    Ok(link) => links.push(link),
+                    Err(why) => warn!(logger, "Skipping a corrupted entry of the message log";
+                        "line" => number + 1,
+                        "reason" => format!("{}", why)
+                    ),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        info!(logger, "Message log loaded";
+            "path" => path.to_string_lossy().into_owned(),
+            "links" => links.len()
+        );
+
+        let store = Self {
+            path: path.to_owned(),
+            retention,
+            state: Mutex::new(LogState {
+                links,
+                file,
+                appended: 0,
+            }),
+            logger,
+        };
+        store.compact()?;
+        Ok(store)
+    }
+
+    fn rewrite(&self, state: &mut LogState) -> UResult {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for link in state.links.iter() {
+                writeln!(tmp, "{}", serde_json::to_string(link)?)?;
+            }
+            tmp.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.appended = 0;
+        Ok(())
+    }
+}
+
+impl MessageStore for LogMessageStore {
+    fn record(&self, link: MessageLink) -> UResult {
+        let needs_compaction = {
+            let mut state = self.state.lock().map_err(|_| "Message store lock poisoned")?;
+            writeln!(state.file, "{}", serde_json::to_string(&link)?)?;
+            state.file.flush()?;
+            state.links.push(link);
+            state.appended += 1;
+            state.appended >= COMPACTION_THRESHOLD
+        };
+        if needs_compaction {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn find_by_telegram(&self, msg: &TelegramMessageRef) -> UResult<Vec<MessageLink>> {
+        let state = self.state.lock().map_err(|_| "Message store lock poisoned")?;
+        Ok(state
+            .links
+            .iter()
+            .filter(|link| &link.telegram == msg)
+            .cloned()
+            .collect())
+    }
+
+    fn find_by_discord(
+        &self,
+        server: &str,
+        channel: &str,
+        message_id: &str,
+    ) -> UResult<Vec<MessageLink>> {
+        let state = self.state.lock().map_err(|_| "Message store lock poisoned")?;
+        Ok(state
+            .links
+            .iter()
+            .filter(|link| matches_discord(link, server, channel, message_id))
+            .cloned()
+            .collect())
+    }
+
+    fn compact(&self) -> UResult<usize> {
+        let mut state = self.state.lock().map_err(|_| "Message store lock poisoned")?;
+        let threshold = MessageLink::now() - self.retention.as_secs() as i64;
+        let before = state.links.len();
+        state.links.retain(|link| link.relayed_at >= threshold);
+        let removed = before - state.links.len();
+        self.rewrite(&mut state)?;
+        debug!(self.logger, "Message log compacted";
+            "removed" => removed,
+            "kept" => state.links.len()
+        );
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    /// Path of a scratch log unique to the test
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qctg-{}-{}.log", name, std::process::id()))
+    }
+
+    fn logger() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn link(message_id: i64, discord_id: Option<&str>, relayed_at: i64) -> MessageLink {
+        MessageLink {
+            origin: if discord_id.is_some() {
+                LinkOrigin::Discord
+            } else {
+                LinkOrigin::Telegram
+            },
+            telegram: TelegramMessageRef {
+                chat_id: -100,
+                message_id,
+            },
+            discord: DiscordMessageRef {
+                server: "server".to_owned(),
+                channel: "general".to_owned(),
+                message_id: discord_id.map(|id| id.to_owned()),
+            },
+            text: TextPlacement::Text,
+            relayed_at,
+        }
+    }
+
+    fn telegram(message_id: i64) -> TelegramMessageRef {
+        TelegramMessageRef {
+            chat_id: -100,
+            message_id,
+        }
+    }
+
+    #[test]
+    fn references_carry_the_chat() {
+        let original = TelegramMessageRef {
+            chat_id: -1001234,
+            message_id: 100,
+        };
+        assert_eq!(original.reference(), "-1001234:100");
+        assert_eq!(
+            TelegramMessageRef::from_reference(&original.reference()),
+            Some(original)
+        );
+        assert_eq!(TelegramMessageRef::from_reference("100"), None);
+        assert_eq!(TelegramMessageRef::from_reference("-100:abc"), None);
+    }
+
+    #[test]
+    fn links_survive_reopening() {
+        let path = scratch_path("reopen");
+        let retention = Duration::from_secs(DAY as u64);
+        let from_telegram = link(1, None, MessageLink::now());
+        let from_discord = link(2, Some("42"), MessageLink::now());
+        {
+            let store = LogMessageStore::open(&path, retention, logger()).unwrap();
+            store.record(from_telegram.clone()).unwrap();
+            store.record(from_discord.clone()).unwrap();
+        }
+
+        let store = LogMessageStore::open(&path, retention, logger()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(store.find_by_telegram(&telegram(1)).unwrap(), [from_telegram]);
+        assert_eq!(
+            store.find_by_discord("server", "general", "42").unwrap(),
+            [from_discord]
+        );
+        assert!(store.find_by_telegram(&telegram(3)).unwrap().is_empty());
+        assert!(store.find_by_discord("server", "other", "42").unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupted_lines_are_skipped() {
+        let path = scratch_path("corrupted");
+        let first = link(1, None, MessageLink::now());
+        let second = link(2, None, MessageLink::now());
+        let contents = format!(
+            "{}\n{{\"origin\":\"Telegram\",\n\n{}\n",
+            serde_json::to_string(&first).unwrap(),
+            serde_json::to_string(&second).unwrap()
+        );
+        std::fs::write(&path, contents).unwrap();
+
+        let store = LogMessageStore::open(&path, Duration::from_secs(DAY as u64), logger()).unwrap();
+        let rewritten = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(store.find_by_telegram(&telegram(1)).unwrap(), [first]);
+        assert_eq!(store.find_by_telegram(&telegram(2)).unwrap(), [second]);
+        assert_eq!(rewritten.lines().count(), 2);
+    }
+
+    #[test]
+    fn compaction_forgets_outdated_links() {
+        let path = scratch_path("compaction");
+        let now = MessageLink::now();
+        let outdated = link(1, None, now - 3 * DAY);
+        let recent = link(2, None, now - DAY / 2);
+        let contents = format!(
+            "{}\n{}\n",
+            serde_json::to_string(&outdated).unwrap(),
+            serde_json::to_string(&recent).unwrap()
+        );
+        std::fs::write(&path, contents).unwrap();
+
+        let retention = Duration::from_secs(DAY as u64);
+        let store = LogMessageStore::open(&path, retention, logger()).unwrap();
+        assert!(store.find_by_telegram(&telegram(1)).unwrap().is_empty());
+        assert_eq!(store.find_by_telegram(&telegram(2)).unwrap(), std::slice::from_ref(&recent));
+
+        store.record(link(3, None, now - 2 * DAY)).unwrap();
+        assert_eq!(store.compact().unwrap(), 1);
+        assert!(store.find_by_telegram(&telegram(3)).unwrap().is_empty());
+
+        drop(store);
+        let store = LogMessageStore::open(&path, retention, logger()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(store.find_by_telegram(&telegram(1)).unwrap().is_empty());
+        assert_eq!(store.find_by_telegram(&telegram(2)).unwrap(), [recent]);
+    }
+}
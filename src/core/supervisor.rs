@@ -0,0 +1,152 @@
+use slog::Logger;
+
+use crate::prelude::*;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Run time after which a server is considered healthy again
+/// and its count of consecutive failures is reset
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// A server which can be asked to stop listening
+pub trait Supervisable: Send + Sync {
+    /// Request the server to stop, its listening loop is
+    /// expected to return shortly after
+    fn request_stop(&self);
+}
+
+/// How a failed server gets restarted
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Number of consecutive restarts before giving up
+    pub max_restarts: u32,
+    /// Delay before the first restart, doubled on every
+    /// consecutive failure
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two restarts
+    pub max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    /// Delay before the given restart, counted from 1
+    pub fn backoff(&self, restart: u32) -> Duration {
+        let factor = 2u32.saturating_pow(restart.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Supervisor running a server and restarting it with an
+/// exponential backoff whenever it fails
+pub struct Supervisor<T> {
+    name: &'static str,
+    policy: RestartPolicy,
+    current: Mutex<Option<Arc<T>>>,
+    stop_requested: AtomicBool,
+    stop_notify: Notify,
+    logger: Logger,
+}
+
+impl<T: Supervisable> Supervisor<T> {
+    /// Instantiate a supervisor of the named server
+    pub fn new(name: &'static str, policy: RestartPolicy, logger: Logger) -> Self {
+        Self {
+            name,
+            policy,
+            current: Mutex::new(None),
+            stop_requested: AtomicBool::new(false),
+            stop_notify: Notify::new(),
+            logger,
+        }
+    }
+
+    /// Instance of the server started last, if any
+    pub fn current(&self) -> Option<Arc<T>> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Start the server with `start` and run it with `listen`
+    /// until a stop is requested. Returns an error once the
+    /// server failed more times in a row than the policy allows
+    pub async fn run<S, SFut, L, LFut>(&self, start: S, listen: L) -> UResult
+    where
+        S: Fn() -> SFut,
+        SFut: Future<Output = UResult<Arc<T>>>,
+        L: Fn(Arc<T>) -> LFut,
+        LFut: Future<Output = UResult>,
+    {
+        let mut failures = 0u32;
+        loop {
+            info!(self.logger, "Starting the {}", self.name);
+            let started = Instant::now();
+            let result = match start().await {
+                Ok(instance) => {
+                    *self.current.lock().unwrap() = Some(instance.clone());
+                    // A stop requested while starting would be missed
+                    if self.is_stopped() {
+                        instance.request_stop();
+                    }
+                    listen(instance).await
+                }
+                Err(why) => Err(why),
+            };
+
+            if self.is_stopped() {
+                info!(self.logger, "The {} stopped", self.name);
+                return result;
+            }
+            match result {
+                Ok(()) => error!(self.logger, "The {} stopped unexpectedly", self.name),
+                Err(why) => error!(self.logger, "The {} failed: {:#?}", self.name, why),
+            }
+            // The failed instance must release its resources, like
+            // its bound address, before a new one starts
+            *self.current.lock().unwrap() = None;
+
+            if started.elapsed() >= STABLE_RUN {
+                failures = 0;
+            }
+            failures += 1;
+            if failures > self.policy.max_restarts {
+                crit!(self.logger, "The {} keeps failing, giving up", self.name;
+                    "failures" => failures
+                );
+                return Err(format!("The {} failed {} times in a row", self.name, failures).into());
+            }
+
+            let delay = self.policy.backoff(failures);
+            warn!(self.logger, "Restarting the {} after a delay", self.name;
+                "delay_ms" => delay.as_millis() as u64,
+                "restart" => failures,
+                "max_restarts" => self.policy.max_restarts
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => (),
+                _ = self.stop_notify.notified() => {
+                    info!(self.logger, "The {} stopped while waiting for a restart", self.name);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Request the supervised server to stop and the supervisor
+    /// not to restart it anymore
+    pub fn request_stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.stop_notify.notify_one();
+        if let Some(instance) = self.current() {
+            instance.request_stop();
+        }
+    }
+
+    /// Check whether a stop was requested
+    pub fn is_stopped(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+}
@@ -1,6 +1,7 @@
 use crate::prelude::*;
 use chrono;
 use slog::{o, Drain, Logger};
+use slog_async::AsyncGuard;
 
 fn get_datetime_str() -> String {
     chrono::offset::Local::now()
@@ -9,7 +10,7 @@ fn get_datetime_str() -> String {
 }
 
 /// Инициализатор логгера с компактным отображением
-pub fn configure_compact_root() -> UResult<Logger> {
+pub fn configure_compact_root() -> UResult<(Logger, AsyncGuard)> {
     let file = {
         let filename = format!("{}.txt", get_datetime_str());
         let file_path = std::path::Path::new(&filename);
@@ -20,13 +21,13 @@ pub fn configure_compact_root() -> UResult<Logger> {
         .use_local_timestamp()
         .build()
         .fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let (drain, guard) = slog_async::Async::new(drain).build_with_guard();
 
-    Ok(slog::Logger::root(drain, o!()))
+    Ok((slog::Logger::root(drain.fuse(), o!()), guard))
 }
 
 /// Инициализатор логгера с полным отображением
-pub fn configure_full_root() -> UResult<Logger> {
+pub fn configure_full_root() -> UResult<(Logger, AsyncGuard)> {
     let file = {
         let filename = format!("{}.txt", get_datetime_str());
         let file_path = std::path::Path::new(&filename);
@@ -38,19 +39,24 @@ pub fn configure_full_root() -> UResult<Logger> {
         .use_local_timestamp()
         .build()
         .fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let (drain, guard) = slog_async::Async::new(drain).build_with_guard();
 
-    Ok(slog::Logger::root(drain, o!()))
+    Ok((slog::Logger::root(drain.fuse(), o!()), guard))
 }
 
 /// Инициализатор логгера с полным отображением
-pub fn configure_term_root() -> Logger {
+///
+/// Записи с уровнем ниже `level` отбрасываются. Сброс буфера
+/// асинхронного логгера происходит при уничтожении
+/// возвращаемого `AsyncGuard`
+pub fn configure_term_root(level: slog::Level) -> (Logger, AsyncGuard) {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::CompactFormat::new(decorator)
         .use_local_timestamp()
         .build()
         .fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let drain = slog::LevelFilter::new(drain, level).fuse();
+    let (drain, guard) = slog_async::Async::new(drain).build_with_guard();
 
-    slog::Logger::root(drain, o!())
+    (slog::Logger::root(drain.fuse(), o!()), guard)
 }
@@ -1,19 +1,68 @@
 #![allow(unused)]
 
+mod cli;
 mod config;
 mod core;
 mod logger;
 mod prelude;
+mod secret;
 mod utility;
 
 use crate::prelude::*;
+use clap::Parser;
+use std::process::ExitCode;
 
+/// Exits with a success status only after a requested
+/// shutdown completed in time
 #[tokio::main]
-async fn main() -> UResult {
+async fn main() -> ExitCode {
+    let cli = cli::Cli::parse();
+    // Dropping the guard last flushes the pending log records
+    let (logger, _log_guard) = logger::configure_term_root(cli.log_level);
+    let config_sources = config::ConfigSources {
+        path: cli.config.clone(),
+        overrides: cli.overrides.clone(),
+    };
+    // Checking the configuration must leave its file untouched
+    let load_mode = if cli.check_config {
+        config::LoadMode::ReadOnly
+    } else {
+        config::LoadMode::Startup
+    };
+    let config = match config::load(&config_sources, load_mode, &logger) {
+        Ok((config, sources)) => {
+            config::log_sources(&logger, &sources);
+            config
+        }
+        Err(why) => {
+            crit!(logger, "Could not load the configuration: {}", why;
+                "path" => config_sources.path.to_string_lossy().into_owned()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if cli.check_config {
+        return match config::validate(&config) {
+            Ok(()) => {
+                info!(logger, "The configuration is valid");
+                ExitCode::SUCCESS
+            }
+            Err(why) => {
+                crit!(logger, "Invalid configuration: {}", why);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     let requirements = application::BootstrapRequirements {
-        logger: logger::configure_term_root(),
-        config: config::read_or_create("bot_config.toml")?,
+        logger: logger.clone(),
+        config,
+        config_sources,
     };
 
-    application::bootstrap(requirements).await
+    match application::bootstrap(requirements).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::FAILURE,
+    }
 }
@@ -0,0 +1,140 @@
+//! Wrapper masking sensitive settings in the logs
+
+use serde::{Deserialize, Serialize, Serializer};
+use std::cell::Cell;
+
+/// Placeholder shown instead of a secret value
+pub const REDACTED: &str = "[redacted]";
+
+thread_local! {
+    /// Whether the secrets serialized on this thread are masked
+    static REDACTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sensitive value, like a password or a token, masked in the
+/// `Debug` output and in the `redacted` serialization. The plain
+/// serialization keeps the real value so the configuration can
+/// still be written back
+#[derive(Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    /// Wrap a sensitive value
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Real value, it must not end up in the logs
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> std::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T: Serialize> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if REDACTING.with(Cell::get) {
+            serializer.serialize_str(REDACTED)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+/// Resets the redaction flag even if the serialization panics
+struct RedactionGuard;
+
+impl Drop for RedactionGuard {
+    fn drop(&mut self) {
+        REDACTING.with(|r| r.set(false));
+    }
+}
+
+/// Serialize the value for logging, every `Secret` inside
+/// of it is masked
+pub fn redacted<T: Serialize>(value: &T) -> serde_json::Result<serde_json::Value> {
+    REDACTING.with(|r| r.set(true));
+    let _guard = RedactionGuard;
+    serde_json::to_value(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Debug)]
+    struct Credentials {
+        user: String,
+        token: Secret<String>,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct Settings {
+        credentials: Credentials,
+        pins: Vec<Secret<u32>>,
+    }
+
+    fn settings() -> Settings {
+        Settings {
+            credentials: Credentials {
+                user: "bridge".to_owned(),
+                token: Secret::new("hunter2".to_owned()),
+            },
+            pins: vec![Secret::new(1234)],
+        }
+    }
+
+    #[test]
+    fn debug_output_is_masked() {
+        assert_eq!(format!("{:?}", Secret::new("hunter2")), REDACTED);
+        let debug = format!("{:?}", settings());
+        assert!(!debug.contains("hunter2") && !debug.contains("1234"), "{}", debug);
+        assert!(debug.contains("bridge"), "{}", debug);
+    }
+
+    #[test]
+    fn redacted_masks_nested_secrets_and_resets_the_flag() {
+        let masked = redacted(&settings()).unwrap();
+        assert_eq!(masked["credentials"]["user"], "bridge");
+        assert_eq!(masked["credentials"]["token"], REDACTED);
+        assert_eq!(masked["pins"][0], REDACTED);
+
+        let plain = serde_json::to_value(settings()).unwrap();
+        assert_eq!(plain["credentials"]["token"], "hunter2");
+        assert_eq!(plain["pins"][0], 1234);
+    }
+
+    #[derive(Serialize)]
+    struct WithSecret {
+        token: Secret<String>,
+    }
+
+    impl Default for WithSecret {
+        fn default() -> Self {
+            Self {
+                token: Secret::new("hunter2".to_owned()),
+            }
+        }
+    }
+
+    #[test]
+    fn created_configs_keep_the_real_value() {
+        let path = std::env::temp_dir().join(format!("qctg-secret-{}.toml", std::process::id()));
+        crate::config::create::<WithSecret>(&path.to_string_lossy()).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.trim(), "token = \"hunter2\"");
+    }
+}
@@ -22,6 +22,35 @@ pub fn format_user_name(user: &User) -> String {
     }
 }
 
+/// Maximal number of characters of the original message
+/// kept in a reply quote
+const QUOTE_SNIPPET_LEN: usize = 64;
+
+/// First line of a message shortened to the length of a quote
+pub fn snippet(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default();
+    let mut snippet: String = line.chars().take(QUOTE_SNIPPET_LEN).collect();
+    if snippet.len() < content.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Format the first line of a message as a quote used when the
+/// counterpart of a replied message is unknown
+pub fn quote_snippet(author: &str, content: &str) -> String {
+    format!("> {}: {}\n", author, snippet(content))
+}
+
+/// Compare two byte strings in a time independent of their
+/// contents, only their lengths may be inferred from timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn load_input_file(file_path: &str) -> UResult<InputFile> {
     // let file = std::fs::File::open(std::path::Path::new(file_path))?;
     // let mut reader = BufReader::new(file);